@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+use crate::product::Product;
+use crate::Notifier;
+
+// A canonical card, independent of the retailer selling it. Listings in `products` point at one of these through their `catalog_id`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CatalogEntry {
+    pub id: String,
+    pub model: String,
+    pub chip: String,
+    pub brand: String,
+    pub mpn: Option<String>,
+    pub upc: Option<String>,
+}
+
+impl CatalogEntry {
+    pub fn display_name(&self) -> String {
+        format!("{} {} {}", self.brand, self.chip, self.model)
+    }
+}
+
+impl Notifier {
+    // Find the canonical card a listing belongs to, if it has been linked to one
+    pub fn catalog_entry(&self, product: &Product) -> Option<&CatalogEntry> {
+        let id = product.get_catalog_id()?;
+        self.config
+            .catalog
+            .as_ref()?
+            .iter()
+            .find(|entry| entry.id == id)
+    }
+
+    // Get the other active listings for the same card at any retailer
+    pub fn other_listings(&self, product: &Product) -> Vec<&Product> {
+        let id = match product.get_catalog_id() {
+            Some(id) => id,
+            None => return vec![],
+        };
+        let url = product.get_url().ok();
+
+        self.config
+            .products
+            .iter()
+            .filter(|listing| listing.get_catalog_id() == Some(id) && listing.get_url().ok() != url)
+            .collect()
+    }
+
+    // The stock message for a listing, with the card's other listings appended so people can try those too
+    pub fn stock_message(&self, product: &Product) -> String {
+        let mut message = product.new_stock_message();
+        if let Some(also_check) = self.also_check(product) {
+            message.push('\n');
+            message.push_str(&also_check);
+        }
+
+        message
+    }
+
+    // A list of the card's other listings, if it has any
+    pub fn also_check(&self, product: &Product) -> Option<String> {
+        let others = self.other_listings(product);
+        if others.is_empty() {
+            return None;
+        }
+
+        let mut message = "Also check:".to_string();
+        for other in others {
+            if let Ok(url) = other.get_url() {
+                message.push_str(&format!("\n{}: {}", other.to_key(), url));
+            }
+        }
+
+        Some(message)
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Local};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::notifier::{is_dry_run, Alert};
+use crate::product::{tag_expression_matches, Product};
+use crate::subscriber::Subscriber;
+use crate::Notifier;
+
+// Links stop working after this long
+const LINK_LIFETIME_DAYS: i64 = 7;
+// The most links kept at once, the oldest are dropped past this
+const MAX_CLAIM_LINKS: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClaimScope {
+    // The subscriber got one, so only they stop hearing about it
+    Subscriber,
+    // Someone got one for the team, so nobody hears about it
+    Everyone,
+}
+
+// Something a subscriber bought, so we stop sending it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Claim {
+    pub claimed_by: String,
+    pub scope: ClaimScope,
+    // What was claimed. A tag expression covers every product with those tags,
+    // otherwise the card at any retailer, falling back to the one listing
+    pub tag: Option<String>,
+    pub catalog_id: Option<String>,
+    pub listing_id: Option<String>,
+    // Shown in status replies and logs
+    pub product: String,
+    pub claimed_at: DateTime<Local>,
+}
+
+// A product as it was sent to one subscriber, so they can claim it later through a link or by texting
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClaimLink {
+    pub subscriber_id: String,
+    pub product: String,
+    pub catalog_id: Option<String>,
+    pub listing_id: Option<String>,
+    pub sent_at: DateTime<Local>,
+}
+
+impl Claim {
+    pub fn covers(&self, product: &Product) -> bool {
+        if let Some(tag) = &self.tag {
+            return tag_expression_matches(tag, product.get_tags());
+        }
+
+        match (&self.catalog_id, &self.listing_id) {
+            (Some(catalog_id), _) => product.get_catalog_id() == Some(catalog_id.as_str()),
+            (None, Some(listing_id)) => product.listing_id().as_ref() == Some(listing_id),
+            (None, None) => false,
+        }
+    }
+
+    // Whether this claim keeps the product from the subscriber. None checks for claims that keep it from everyone
+    fn applies_to(&self, subscriber_id: Option<&str>) -> bool {
+        self.scope == ClaimScope::Everyone || subscriber_id == Some(self.claimed_by.as_str())
+    }
+
+    // Claiming the same thing again replaces the old claim
+    fn replaces(&self, other: &Claim) -> bool {
+        self.scope == other.scope
+            && (self.scope == ClaimScope::Everyone || self.claimed_by == other.claimed_by)
+            && self.tag == other.tag
+            && self.catalog_id == other.catalog_id
+            && self.listing_id == other.listing_id
+    }
+
+    pub fn describe(&self) -> String {
+        let who = match self.scope {
+            ClaimScope::Everyone => "everyone",
+            ClaimScope::Subscriber => "themselves",
+        };
+        format!(
+            "{} claimed by {} for {} on {}",
+            self.product,
+            self.claimed_by,
+            who,
+            self.claimed_at.format("%b %e %H:%M")
+        )
+    }
+}
+
+impl Notifier {
+    // The claim that keeps this product from the subscriber, if any. None only looks at claims for everyone
+    pub fn claim_for(&self, subscriber_id: Option<&str>, product: &Product) -> Option<&Claim> {
+        self.config
+            .application_config
+            .claims
+            .iter()
+            .flatten()
+            .find(|claim| claim.applies_to(subscriber_id) && claim.covers(product))
+    }
+
+    // Remember the alert was sent to the subscriber and get a link they can open to claim it
+    // Links need the server, so there's nothing to open without one
+    pub fn claim_link(&mut self, subscriber: &Subscriber, alert: &Alert) -> Option<String> {
+        let public_url = self
+            .config
+            .application_config
+            .server
+            .as_ref()?
+            .public_url
+            .trim_end_matches('/')
+            .to_string();
+        // A dry run shows where the link goes without keeping one
+        if is_dry_run() {
+            return Some(format!("{}/claim/dry-run", public_url));
+        }
+
+        let subscriber_id = subscriber.id();
+        let listing_id = alert.product.listing_id();
+        let link = ClaimLink {
+            subscriber_id: subscriber_id.clone(),
+            product: display_name(&alert.product),
+            catalog_id: alert.product.get_catalog_id().map(str::to_string),
+            listing_id: listing_id.clone(),
+            sent_at: Local::now(),
+        };
+        let links = self
+            .config
+            .application_config
+            .claim_links
+            .get_or_insert_with(HashMap::new);
+        let expired = Local::now() - Duration::days(LINK_LIFETIME_DAYS);
+        links.retain(|_, link| link.sent_at > expired);
+
+        // The same listing sent to the same subscriber again keeps its link, so it isn't stored twice
+        let existing = links
+            .iter()
+            .find(|(_, existing)| {
+                existing.subscriber_id == subscriber_id
+                    && existing.listing_id == listing_id
+                    && existing.product == link.product
+            })
+            .map(|(token, _)| token.clone());
+        let token = existing.unwrap_or_else(|| {
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(20)
+                .collect::<String>()
+        });
+        links.insert(token.clone(), link);
+
+        if links.len() > MAX_CLAIM_LINKS {
+            let mut oldest = links
+                .iter()
+                .map(|(token, link)| (link.sent_at, token.clone()))
+                .collect::<Vec<_>>();
+            oldest.sort();
+            for (_, token) in oldest.into_iter().take(links.len() - MAX_CLAIM_LINKS) {
+                links.remove(&token);
+            }
+        }
+
+        Some(format!("{}/claim/{}", public_url, token))
+    }
+
+    pub fn find_claim_link(&self, token: &str) -> Option<&ClaimLink> {
+        let link = self
+            .config
+            .application_config
+            .claim_links
+            .as_ref()?
+            .get(token)?;
+        if link.sent_at > Local::now() - Duration::days(LINK_LIFETIME_DAYS) {
+            Some(link)
+        } else {
+            None
+        }
+    }
+
+    // The last product sent to the subscriber, which is what a bare CLAIM means
+    pub fn latest_claim_link(&self, subscriber_id: &str) -> Option<&ClaimLink> {
+        self.config
+            .application_config
+            .claim_links
+            .iter()
+            .flat_map(|links| links.values())
+            .filter(|link| link.subscriber_id == subscriber_id)
+            .max_by_key(|link| link.sent_at)
+    }
+
+    // Claim what a link points at. Claiming something also acknowledges it
+    pub fn claim_linked(&mut self, link: &ClaimLink, scope: ClaimScope) -> Claim {
+        self.add_claim(Claim {
+            claimed_by: link.subscriber_id.clone(),
+            scope,
+            tag: None,
+            catalog_id: link.catalog_id.clone(),
+            listing_id: link.listing_id.clone(),
+            product: link.product.clone(),
+            claimed_at: Local::now(),
+        })
+    }
+
+    // Claim every product with the tags
+    pub fn claim_tag(&mut self, subscriber_id: &str, tag: &str, scope: ClaimScope) -> Claim {
+        self.add_claim(Claim {
+            claimed_by: subscriber_id.to_string(),
+            scope,
+            tag: Some(tag.to_string()),
+            catalog_id: None,
+            listing_id: None,
+            product: format!("products tagged {}", tag),
+            claimed_at: Local::now(),
+        })
+    }
+
+    // Drop the subscriber's claims on the tag, or their latest claim when there's no tag
+    pub fn unclaim(&mut self, subscriber_id: &str, tag: Option<&str>) -> Vec<Claim> {
+        let claims = match &mut self.config.application_config.claims {
+            Some(claims) => claims,
+            None => return vec![],
+        };
+
+        let mut removed = vec![];
+        match tag {
+            Some(tag) => claims.retain(|claim| {
+                let matches =
+                    claim.claimed_by == subscriber_id && claim.tag.as_deref() == Some(tag);
+                if matches {
+                    removed.push(claim.clone());
+                }
+                !matches
+            }),
+            None => {
+                let latest = claims
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, claim)| claim.claimed_by == subscriber_id)
+                    .max_by_key(|(_, claim)| claim.claimed_at)
+                    .map(|(i, _)| i);
+                if let Some(i) = latest {
+                    removed.push(claims.remove(i));
+                }
+            }
+        }
+
+        for claim in &removed {
+            println!("{} unclaimed {}", subscriber_id, claim.product);
+        }
+        removed
+    }
+
+    // Claims the subscriber would want to know about, theirs and the team's
+    pub fn visible_claims(&self, subscriber_id: &str) -> Vec<&Claim> {
+        self.config
+            .application_config
+            .claims
+            .iter()
+            .flatten()
+            .filter(|claim| claim.applies_to(Some(subscriber_id)))
+            .collect()
+    }
+
+    fn add_claim(&mut self, claim: Claim) -> Claim {
+        println!("{}", claim.describe());
+        self.acknowledge(&claim.claimed_by);
+        let claims = self
+            .config
+            .application_config
+            .claims
+            .get_or_insert_with(Vec::new);
+        claims.retain(|existing| !claim.replaces(existing));
+        claims.push(claim.clone());
+        claim
+    }
+}
+
+fn display_name(product: &Product) -> String {
+    match product.get_name() {
+        Ok(name) => format!("{} {}", product.retailer_name(), name),
+        Err(_) => product.retailer_name().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+
+    #[test]
+    fn links_are_reused_and_capped() {
+        let mut notifier = stub::notifier(
+            serde_json::json!({ "server": { "listen": "127.0.0.1:0", "public_url": "https://example.com/" } }),
+            serde_json::json!([{ "service": ["bestbuy"], "active": true, "to_phone_number": "+15551112222" }]),
+        );
+        let subscriber = notifier.config.subscribers[0].clone();
+        let alert = stub::alert(stub::product("RTX 3080"));
+
+        let first = notifier.claim_link(&subscriber, &alert).unwrap();
+        let again = notifier.claim_link(&subscriber, &alert).unwrap();
+        assert_eq!(first, again);
+        assert!(first.starts_with("https://example.com/claim/"));
+
+        for i in 0..MAX_CLAIM_LINKS + 10 {
+            let alert = stub::alert(stub::product(&format!("RTX {}", i)));
+            notifier.claim_link(&subscriber, &alert);
+        }
+        let links = notifier
+            .config
+            .application_config
+            .claim_links
+            .as_ref()
+            .unwrap();
+        assert_eq!(links.len(), MAX_CLAIM_LINKS);
+        assert!(!links.contains_key(first.rsplit('/').next().unwrap()));
+    }
+}
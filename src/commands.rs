@@ -0,0 +1,380 @@
+use crate::{claim::ClaimScope, product::Product, subscriber::Subscriber, Notifier};
+
+const HELP: &str = "Commands: STOP, START, ADD <retailer, card or tag>, REMOVE <retailer, card or tag>, STATUS, ACK, BOUGHT [tag], CLAIM [tag], UNCLAIM [tag]";
+
+// What a subscriber can text us
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Stop all alerts
+    Stop,
+    // Turn alerts back on, texts included
+    Start,
+    Add(String),
+    Remove(String),
+    Status,
+    // Cancel any call we're about to place
+    Ack,
+    // The subscriber got the last product they were sent, or anything with the tag, and wants no more
+    Bought(Option<String>),
+    // Like bought, but nobody on the team gets any more
+    Claim(Option<String>),
+    // Undo the subscriber's claim on the tag, or their last one
+    Unclaim(Option<String>),
+    Help,
+}
+
+impl Command {
+    pub fn parse(text: &str) -> Command {
+        let text = text.trim();
+        let (word, argument) = match text.find(char::is_whitespace) {
+            Some(i) => (&text[..i], text[i..].trim().to_lowercase()),
+            None => (text, String::new()),
+        };
+
+        let tag = Some(argument.clone()).filter(|tag| !tag.is_empty());
+        match (word.to_uppercase().as_str(), argument.is_empty()) {
+            ("STOP", _) | ("UNSUBSCRIBE", _) => Command::Stop,
+            ("START", _) | ("UNSTOP", _) => Command::Start,
+            ("ADD", false) => Command::Add(argument),
+            ("REMOVE", false) => Command::Remove(argument),
+            ("STATUS", _) => Command::Status,
+            ("ACK", _) | ("OK", _) => Command::Ack,
+            ("BOUGHT", _) => Command::Bought(tag),
+            ("CLAIM", _) => Command::Claim(tag),
+            ("UNCLAIM", _) => Command::Unclaim(tag),
+            _ => Command::Help,
+        }
+    }
+}
+
+// The kinds of thing ADD and REMOVE work on
+enum Interest {
+    // A provider key, like "amazon"
+    Provider(String),
+    // A catalog id, for the card at any retailer
+    Card(String),
+    // A tag expression, like "3090"
+    Tag(String),
+}
+
+impl Notifier {
+    // Run a command texted in by a subscriber, returning the reply
+    pub fn run_sms_command(&mut self, from: &str, text: &str) -> String {
+        // Replies over WhatsApp come from the same number with a prefix
+        let from = from.trim_start_matches("whatsapp:");
+        let command = Command::parse(text);
+        println!("{} texted {:?}", from, command);
+
+        match &command {
+            Command::Ack => self.acknowledge(from),
+            Command::Bought(tag) => {
+                return self.claim_command(from, tag.as_deref(), ClaimScope::Subscriber)
+            }
+            Command::Claim(tag) => {
+                return self.claim_command(from, tag.as_deref(), ClaimScope::Everyone)
+            }
+            Command::Unclaim(tag) => {
+                return match self.unclaim(from, tag.as_deref()).as_slice() {
+                    [] => "You haven't claimed that.".to_string(),
+                    removed => format!(
+                        "Alerts are back on for {}.",
+                        removed
+                            .iter()
+                            .map(|claim| claim.product.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    ),
+                }
+            }
+            _ => {}
+        }
+        let interest = match &command {
+            Command::Add(item) | Command::Remove(item) => Some(self.interest(item)),
+            _ => None,
+        };
+        let claims = self
+            .visible_claims(from)
+            .iter()
+            .map(|claim| claim.describe())
+            .collect::<Vec<String>>();
+        let call_pending = self
+            .config
+            .application_config
+            .pending_escalations
+            .iter()
+            .flatten()
+            .any(|escalation| escalation.subscriber_id == from);
+
+        let subscriber = match self
+            .config
+            .subscribers
+            .iter_mut()
+            .find(|subscriber| subscriber.to_phone_number.as_deref() == Some(from))
+        {
+            Some(subscriber) => subscriber,
+            None => return "This number isn't subscribed to any alerts.".to_string(),
+        };
+
+        match (command, interest) {
+            (Command::Stop, _) => {
+                subscriber.active = false;
+                "Alerts are off. Text START to turn them back on.".to_string()
+            }
+            (Command::Start, _) => {
+                subscriber.active = true;
+                subscriber.sms_disabled = None;
+                "Alerts are on. Text HELP for commands.".to_string()
+            }
+            (Command::Add(_), Some(interest)) => add(subscriber, interest),
+            (Command::Remove(_), Some(interest)) => remove(subscriber, interest),
+            (Command::Status, _) => status(subscriber, &claims, call_pending),
+            (Command::Ack, _) => "Got it, no call is coming.".to_string(),
+            _ => HELP.to_string(),
+        }
+    }
+
+    fn claim_command(&mut self, from: &str, tag: Option<&str>, scope: ClaimScope) -> String {
+        if !self
+            .config
+            .subscribers
+            .iter()
+            .any(|subscriber| subscriber.to_phone_number.as_deref() == Some(from))
+        {
+            return "This number isn't subscribed to any alerts.".to_string();
+        }
+
+        let claim = match tag {
+            Some(tag) => self.claim_tag(from, tag, scope),
+            None => match self.latest_claim_link(from).cloned() {
+                Some(link) => self.claim_linked(&link, scope),
+                None => return "Nothing to claim yet, text CLAIM <tag> instead.".to_string(),
+            },
+        };
+
+        match scope {
+            ClaimScope::Subscriber => format!("Congrats! No more alerts for {}.", claim.product),
+            ClaimScope::Everyone => format!(
+                "Congrats! Nobody will get more alerts for {}.",
+                claim.product
+            ),
+        }
+    }
+
+    fn interest(&self, item: &str) -> Interest {
+        if Product::KEYS.contains(&item) {
+            return Interest::Provider(item.to_string());
+        }
+
+        match self
+            .config
+            .catalog
+            .iter()
+            .flatten()
+            .find(|entry| entry.id.eq_ignore_ascii_case(item))
+        {
+            Some(entry) => Interest::Card(entry.id.clone()),
+            None => Interest::Tag(item.to_string()),
+        }
+    }
+}
+
+fn add(subscriber: &mut Subscriber, interest: Interest) -> String {
+    match interest {
+        Interest::Provider(key) => {
+            if !subscriber.service.contains(&key) {
+                subscriber.service.push(key.clone());
+            }
+            format!("Now sending {} alerts.", key)
+        }
+        Interest::Card(id) => {
+            let catalog = subscriber.catalog.get_or_insert_with(Vec::new);
+            if !catalog.contains(&id) {
+                catalog.push(id.clone());
+            }
+            format!("Now sending {} from any retailer.", id)
+        }
+        Interest::Tag(tag) => {
+            // Only ever widens what's sent. An empty include list already lets everything through,
+            // so the tag is only added to filters that list what they want
+            let mut widened = false;
+            for filter in subscriber
+                .tags
+                .iter_mut()
+                .flat_map(|tags| tags.values_mut())
+            {
+                if let Some(exclude) = &mut filter.exclude {
+                    let before = exclude.len();
+                    exclude.retain(|expr| expr != &tag);
+                    widened |= exclude.len() != before;
+                }
+                if let Some(include) = filter.include.as_mut().filter(|i| !i.is_empty()) {
+                    if !include.contains(&tag) {
+                        include.push(tag.clone());
+                        widened = true;
+                    }
+                }
+            }
+            if widened {
+                format!("Now sending products tagged {}.", tag)
+            } else {
+                format!("Already sending products tagged {}.", tag)
+            }
+        }
+    }
+}
+
+fn remove(subscriber: &mut Subscriber, interest: Interest) -> String {
+    match interest {
+        Interest::Provider(key) => {
+            subscriber.service.retain(|service| service != &key);
+            format!("No more {} alerts.", key)
+        }
+        Interest::Card(id) => {
+            if let Some(catalog) = &mut subscriber.catalog {
+                catalog.retain(|card| card != &id);
+            }
+            format!("No more alerts for {}.", id)
+        }
+        Interest::Tag(tag) => {
+            // Every provider's filter has to leave it out, the "*" one covers providers without their own
+            subscriber
+                .tags
+                .get_or_insert_with(Default::default)
+                .entry("*".to_string())
+                .or_default();
+            for filter in subscriber
+                .tags
+                .iter_mut()
+                .flat_map(|tags| tags.values_mut())
+            {
+                // The last included tag stays, an empty include list would let everything else through
+                if let Some(include) = filter.include.as_mut().filter(|i| i.len() > 1) {
+                    include.retain(|expr| expr != &tag);
+                }
+                let exclude = filter.exclude.get_or_insert_with(Vec::new);
+                if !exclude.contains(&tag) {
+                    exclude.push(tag.clone());
+                }
+            }
+            format!("No more products tagged {}.", tag)
+        }
+    }
+}
+
+fn status(subscriber: &Subscriber, claims: &[String], call_pending: bool) -> String {
+    let mut lines = vec![];
+    lines.push(match (subscriber.active, &subscriber.sms_disabled) {
+        (false, _) => "Alerts are off.".to_string(),
+        (true, Some(reason)) => format!("Alerts are on, but texts are off: {}", reason),
+        (true, None) => "Alerts are on.".to_string(),
+    });
+
+    if !subscriber.service.is_empty() {
+        lines.push(format!("Retailers: {}", subscriber.service.join(", ")));
+    }
+    if let Some(catalog) = subscriber.catalog.as_ref().filter(|c| !c.is_empty()) {
+        lines.push(format!("Cards: {}", catalog.join(", ")));
+    }
+    if let Some(filter) = subscriber.tags.as_ref().and_then(|tags| tags.get("*")) {
+        if let Some(include) = filter.include.as_ref().filter(|i| !i.is_empty()) {
+            lines.push(format!("Tags: {}", include.join(", ")));
+        }
+        if let Some(exclude) = filter.exclude.as_ref().filter(|e| !e.is_empty()) {
+            lines.push(format!("Never: {}", exclude.join(", ")));
+        }
+    }
+    for claim in claims {
+        lines.push(format!("Claimed: {}", claim));
+    }
+    if call_pending {
+        lines.push("A call is coming about an alert, text ACK to cancel it.".to_string());
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::product::ProductDetails;
+    use crate::stub;
+
+    fn tagged(tag: &str) -> Product {
+        Product::BestBuy(ProductDetails {
+            product: format!("RTX {}", tag),
+            page: format!("https://www.bestbuy.com/site/{}", tag),
+            tags: Some(vec![tag.to_string()]),
+            ..ProductDetails::default()
+        })
+    }
+
+    fn texting(subscriber: serde_json::Value) -> Notifier {
+        stub::notifier(serde_json::json!({}), serde_json::json!([subscriber]))
+    }
+
+    #[test]
+    fn commands_ignore_case_and_spacing() {
+        assert_eq!(Command::parse("STOP"), Command::Stop);
+        assert_eq!(Command::parse("  stop \n"), Command::Stop);
+        assert_eq!(Command::parse("Unsubscribe"), Command::Stop);
+        assert_eq!(Command::parse("start"), Command::Start);
+        assert_eq!(Command::parse("UNSTOP"), Command::Start);
+        assert_eq!(Command::parse("status "), Command::Status);
+        assert_eq!(
+            Command::parse("ADD amazon"),
+            Command::Add("amazon".to_string())
+        );
+        assert_eq!(
+            Command::parse(" add   RTX 3090  "),
+            Command::Add("rtx 3090".to_string())
+        );
+        assert_eq!(
+            Command::parse("Remove\t3080+FE"),
+            Command::Remove("3080+fe".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_or_incomplete_commands_get_help() {
+        assert_eq!(Command::parse("ADD"), Command::Help);
+        assert_eq!(Command::parse("remove  "), Command::Help);
+        assert_eq!(Command::parse("stopp"), Command::Help);
+        assert_eq!(Command::parse(""), Command::Help);
+    }
+
+    #[test]
+    fn adding_a_tag_never_narrows_alerts() {
+        let mut notifier = texting(serde_json::json!({
+            "service": ["bestbuy"],
+            "active": true,
+            "to_phone_number": "+15551112222",
+        }));
+
+        let reply = notifier.run_sms_command("+15551112222", "ADD 3090");
+
+        assert_eq!(reply, "Already sending products tagged 3090.");
+        assert!(notifier.config.subscribers[0].wants(&tagged("3080")));
+        assert!(notifier.config.subscribers[0].wants(&tagged("3090")));
+    }
+
+    #[test]
+    fn tags_apply_to_provider_filters_too() {
+        let mut notifier = texting(serde_json::json!({
+            "service": ["bestbuy"],
+            "active": true,
+            "to_phone_number": "+15551112222",
+            "tags": { "bestbuy": { "include": ["3080"] } },
+        }));
+
+        let reply = notifier.run_sms_command("+15551112222", "add 3090");
+        assert_eq!(reply, "Now sending products tagged 3090.");
+        assert!(notifier.config.subscribers[0].wants(&tagged("3080")));
+        assert!(notifier.config.subscribers[0].wants(&tagged("3090")));
+
+        let reply = notifier.run_sms_command("+15551112222", "remove 3080");
+        assert_eq!(reply, "No more products tagged 3080.");
+        assert!(!notifier.config.subscribers[0].wants(&tagged("3080")));
+        assert!(notifier.config.subscribers[0].wants(&tagged("3090")));
+        assert!(!notifier.config.subscribers[0].wants(&tagged("3070")));
+    }
+}
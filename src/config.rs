@@ -8,17 +8,33 @@ use serde::{Deserialize, Serialize};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use crate::catalog::CatalogEntry;
+use crate::claim::{Claim, ClaimLink};
+use crate::mail::{self, MailRule};
+use crate::notifier::budget::{AdminContact, Budget, SpendLog};
+use crate::notifier::discord::{DiscordTarget, PendingWebhook};
+use crate::notifier::email::{self, SmtpSecurity};
+use crate::notifier::mqtt::{MqttConfig, MqttPublisher};
+use crate::notifier::push::PushTarget;
+use crate::notifier::template::{self, Templates};
+use crate::notifier::twilio::{self, TextChannel, TextRecord};
+use crate::notifier::voice::{CallRecord, Escalation, EscalationPolicy};
+use crate::notifier::webhook::WebhookConfig;
+use crate::notifier::{is_dry_run, set_dry_run};
 use crate::product::Product;
-use crate::Subscriber;
+use crate::server::ServerConfig;
+use crate::subscriber::{self, Subscriber};
 use crate::{error::NotifyError, Notifier};
 
 const CONFIG_FILE_PATH: &str = "./config.json";
+const DEFAULT_SNAPSHOT_DIR: &str = "./snapshots";
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
     pub application_config: ApplicationConfig,
     pub subscribers: Vec<Subscriber>,
     pub products: Vec<Product>,
+    pub catalog: Option<Vec<CatalogEntry>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -29,18 +45,94 @@ pub struct ApplicationConfig {
     pub last_notification_sent: DateTime<Local>,
     pub twilio_auth_token: Option<String>,
     pub twilio_account_id: Option<String>,
+    // Defaults to Twilio's API, point it at a mock for testing
+    pub twilio_api_url: Option<String>,
+    // Check on sent texts each cycle to see whether they were delivered
+    pub twilio_poll_status: Option<bool>,
+    // The texts sent to each subscriber and what happened to them, keyed by subscriber id
+    pub sms_log: Option<HashMap<String, Vec<TextRecord>>>,
+    // Phone calls for high priority alerts that haven't been acknowledged
+    pub escalation: Option<EscalationPolicy>,
+    pub pending_escalations: Option<Vec<Escalation>>,
+    // The calls placed to each subscriber and how they went, keyed by subscriber id
+    pub call_log: Option<HashMap<String, Vec<CallRecord>>>,
+    // What Twilio charges and how much can be spent on it, see `budget::Budget`
+    pub budget: Option<Budget>,
+    // Who hears about the budget running out
+    pub admin: Option<AdminContact>,
+    // Texts and calls sent each day, keyed by date and then subscriber id
+    pub spend_log: Option<SpendLog>,
+    // Budget warnings already sent, so each goes out once
+    pub budget_warnings: Option<Vec<String>>,
+    // Listen for texts from subscribers, see `server::ServerConfig`
+    pub server: Option<ServerConfig>,
+    // Products subscribers have bought, which are no longer sent to them or the team
+    pub claims: Option<Vec<Claim>>,
+    // The claim links sent out recently, keyed by the token in the link
+    pub claim_links: Option<HashMap<String, ClaimLink>>,
     pub imap_username: Option<String>,
     pub imap_password: Option<String>,
     pub imap_host: Option<String>,
     pub imap_port: Option<u16>,
+    // Watch for mail as it arrives instead of checking once a cycle, on by default in daemon mode
+    pub imap_idle: Option<bool>,
+    // Seconds between checks for servers that can't tell us about new mail, defaults to 30
+    pub imap_poll_interval: Option<u64>,
+    // Emails that turn into alerts, see `mail::MailRule`. Without any, subjects mentioning evga or newegg do
+    pub mail_rules: Option<Vec<MailRule>>,
+    // When each mail rule last matched an email, keyed by rule name
+    pub mail_last_seen: Option<HashMap<String, DateTime<Local>>>,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub smtp_security: Option<SmtpSecurity>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_from_address: Option<String>,
     pub from_phone_number: Option<String>,
     pub should_open_browser: bool,
+    // Log every message instead of sending it, same as running with --dry-run
+    pub dry_run: Option<bool>,
     pub daemon_mode: bool,
     pub daemon_timeout: Option<u64>,
     pub discord_url: Option<String>,
+    // More Discord webhooks, each only getting the products that match its filters
+    pub discord_targets: Option<Vec<DiscordTarget>>,
+    // Avatar for the Discord webhooks, defaults to the one set on each webhook itself
+    pub discord_avatar_url: Option<String>,
+    // Mentions like "<@&role id>" or "<@user id>", keyed by the product tag expression they want pinged for
+    pub discord_mentions: Option<HashMap<String, Vec<String>>>,
+    // Discord messages waiting to be sent, or sent again after failing
+    pub discord_queue: Option<Vec<PendingWebhook>>,
+    pub slack_urls: Option<Vec<String>>,
+    pub webhooks: Option<Vec<WebhookConfig>>,
+    pub telegram_bot_token: Option<String>,
+    // Chats that get every alert, on top of subscribers' own chats
+    pub telegram_chat_ids: Option<Vec<i64>>,
+    // Defaults to the public Bot API
+    pub telegram_api_url: Option<String>,
+    // Named push notification targets subscribers can pick from
+    pub push_targets: Option<HashMap<String, PushTarget>>,
+    // Tag expressions for products that get the loudest alerts
+    pub high_priority_tags: Option<Vec<String>>,
     pub scraping_timeout: Option<DateTime<Local>>,
     pub ratelimit_keys: Option<HashMap<String, DateTime<Local>>>,
     pub proxy_url: Option<String>,
+    // Messages held back during subscribers' quiet hours, keyed by subscriber id
+    pub deferred_messages: Option<HashMap<String, Vec<String>>>,
+    // Longest text message to send before splitting it up, per channel. All default to Twilio's limit
+    pub sms_max_length: Option<usize>,
+    pub mms_max_length: Option<usize>,
+    pub whatsapp_max_length: Option<usize>,
+    // The WhatsApp enabled sender, defaults to from_phone_number
+    pub whatsapp_from_number: Option<String>,
+    // Publishes product availability for Home Assistant and other MQTT consumers
+    pub mqtt: Option<MqttConfig>,
+    // Message templates per channel and locale. Channels without one use the built in messages
+    pub templates: Option<Templates>,
+    // Locale used for broadcast channels and subscribers without their own
+    pub locale: Option<String>,
+    // Where the page snapshots behind each alert are saved, defaults to ./snapshots
+    pub snapshot_dir: Option<String>,
 }
 
 impl ApplicationConfig {
@@ -54,6 +146,31 @@ impl ApplicationConfig {
             && self.from_phone_number.is_some()
     }
 
+    pub fn text_max_length(&self, channel: TextChannel) -> usize {
+        match channel {
+            TextChannel::Sms => self.sms_max_length,
+            TextChannel::Mms => self.mms_max_length,
+            TextChannel::Whatsapp => self.whatsapp_max_length,
+        }
+        .unwrap_or(twilio::MAX_MESSAGE_LENGTH)
+    }
+
+    pub fn twilio_api_url(&self) -> &str {
+        self.twilio_api_url
+            .as_deref()
+            .unwrap_or(twilio::DEFAULT_API_URL)
+    }
+
+    pub fn telegram_api_url(&self) -> &str {
+        self.telegram_api_url
+            .as_deref()
+            .unwrap_or(crate::notifier::telegram::DEFAULT_API_URL)
+    }
+
+    pub fn snapshot_dir(&self) -> &str {
+        self.snapshot_dir.as_deref().unwrap_or(DEFAULT_SNAPSHOT_DIR)
+    }
+
     pub fn has_imap_config(&self) -> bool {
         self.imap_host.is_some()
             && self.imap_username.is_some()
@@ -61,6 +178,15 @@ impl ApplicationConfig {
             && self.imap_port.is_some()
     }
 
+    // Whether mail is left to the listener instead of being checked each cycle
+    pub fn has_mail_listener(&self) -> bool {
+        self.daemon_mode && self.has_imap_config() && self.imap_idle != Some(false)
+    }
+
+    pub fn has_smtp_config(&self) -> bool {
+        self.smtp_host.is_some() && self.smtp_from_address.is_some()
+    }
+
     pub fn should_open_browser(&self) -> bool {
         self.should_open_browser
     }
@@ -100,15 +226,24 @@ impl Notifier {
 
         // Use serde to deserialize the config
         let config: Config = serde_json::from_str(&buf).map_err(NotifyError::ConfigParse)?;
+        if config.application_config.dry_run == Some(true) {
+            set_dry_run(true);
+        }
+        subscriber::validate(&config.subscribers)?;
+        if let Some(templates) = &config.application_config.templates {
+            template::validate(templates)?;
+        }
 
-        // If the imap config exists, get the imap session
-        let imap = if config.application_config.has_imap_config() {
-            Some(get_imap(
+        // If the imap config exists, get the imap session. The mail listener connects on its own
+        let imap = if config.application_config.has_imap_config()
+            && !config.application_config.has_mail_listener()
+        {
+            Some(std::sync::Mutex::new(get_imap(
                 &config.application_config.imap_host.as_ref().unwrap(),
                 config.application_config.imap_port.unwrap(),
                 &config.application_config.imap_username.as_ref().unwrap(),
                 &config.application_config.imap_password.as_ref().unwrap(),
-            )?)
+            )?))
         } else {
             None
         };
@@ -126,15 +261,58 @@ impl Notifier {
                     .twilio_auth_token
                     .as_ref()
                     .unwrap(),
+                config.application_config.twilio_api_url(),
             ))
         } else {
             None
         };
 
+        // If we have an smtp config build the transport, it connects when it sends
+        let smtp = if config.application_config.has_smtp_config() {
+            let security = config
+                .application_config
+                .smtp_security
+                .unwrap_or(SmtpSecurity::StartTls);
+            let port = config
+                .application_config
+                .smtp_port
+                .unwrap_or(match security {
+                    SmtpSecurity::Tls => 465,
+                    SmtpSecurity::StartTls => 587,
+                    SmtpSecurity::None => 25,
+                });
+            Some(email::get_smtp(
+                config.application_config.smtp_host.as_ref().unwrap(),
+                port,
+                security,
+                config.application_config.smtp_username.as_deref(),
+                config.application_config.smtp_password.as_deref(),
+            )?)
+        } else {
+            None
+        };
+
+        // If we have an mqtt config connect to the broker and announce our products
+        let mqtt = match &config.application_config.mqtt {
+            Some(mqtt_config) => {
+                let mqtt = MqttPublisher::connect(mqtt_config);
+                mqtt.spawn_discovery(config.products.clone());
+                Some(mqtt)
+            }
+            None => None,
+        };
+
+        let mail_rules = mail::build_matchers(config.application_config.mail_rules.as_deref())?;
+
         // And return our built notifier
         Ok(Notifier {
             imap,
             twilio,
+            smtp,
+            mqtt,
+            mail_rules,
+            delivery_failures: vec![],
+            spend_ledger: Default::default(),
             config,
         })
     }
@@ -163,6 +341,11 @@ pub fn get_imap(
 }
 
 pub async fn write_config(notifier: &mut Notifier) -> Result<(), NotifyError> {
+    // A dry run leaves everything as it found it
+    if is_dry_run() {
+        return Ok(());
+    }
+
     // Open the config file, creating it if it doesn't exist
     let mut file = File::create(CONFIG_FILE_PATH)
         .await
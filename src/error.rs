@@ -9,14 +9,23 @@ pub enum NotifyError {
     ImapLogin,
     MailboxLoad,
     EmailFetch,
+    MailRule(String),
 
     // Twilio Related Errors
-    TwilioSend(twilio::TwilioError),
+    TwilioSend(crate::notifier::twilio::TwilioError),
+    TwilioResponse(serde_json::Error),
     ConfigUpdate,
 
+    // Email Sending Errors
+    EmailBuild,
+    SmtpConnection(Box<lettre::transport::smtp::Error>),
+    SmtpSend(Box<lettre::transport::smtp::Error>),
+    SmtpTask(tokio::task::JoinError),
+
     // Config Errors
     ConfigLoad(std::io::Error),
     ConfigParse(serde_json::Error),
+    SubscriberConfig(String),
     NoneCSSSelector,
     NonePage,
 
@@ -33,6 +42,17 @@ pub enum NotifyError {
     IOEncoding(std::string::FromUtf8Error),
     ClientBuild,
     ProxyNotRunning,
+    WebhookConfig,
+
+    // MQTT Errors
+    MqttPublish(Box<rumqttc::ClientError>),
+
+    // Message Template Errors
+    Template(Box<minijinja::Error>),
+
+    // Inbound Server Errors
+    ServerConfig,
+    Server(hyper::Error),
 
     // OS Command Errors
     CommandErr(std::io::Error),
@@ -48,10 +68,17 @@ impl fmt::Display for NotifyError {
             NotifyError::ImapConnection(e) => write!(f, "ImapConnection: {}", e),
             NotifyError::MailboxLoad => write!(f, "MailboxLoad"),
             NotifyError::EmailFetch => write!(f, "EmailFetch"),
+            NotifyError::MailRule(e) => write!(f, "MailRule: {}", e),
             NotifyError::ConfigLoad(e) => write!(f, "ConfigLoad: {}", e),
             NotifyError::ConfigParse(e) => write!(f, "ConfigParse: {}", e),
+            NotifyError::SubscriberConfig(e) => write!(f, "SubscriberConfig: {}", e),
             NotifyError::TwilioSend(e) => write!(f, "TwilioSend: {}", e),
+            NotifyError::TwilioResponse(e) => write!(f, "TwilioResponse: {}", e),
             NotifyError::ConfigUpdate => write!(f, "ConfigUpdate"),
+            NotifyError::EmailBuild => write!(f, "EmailBuild"),
+            NotifyError::SmtpConnection(e) => write!(f, "SmtpConnection: {}", e),
+            NotifyError::SmtpSend(e) => write!(f, "SmtpSend: {}", e),
+            NotifyError::SmtpTask(e) => write!(f, "SmtpTask: {}", e),
             // NotifyError::EmailSubjectParse => write!(f, "EmailSubjectParse"),
             NotifyError::WebRequestFailed(e) => write!(f, "WebRequestFailed: {}", e),
             NotifyError::HTMLParseFailed => write!(f, "HTMLParseFailed"),
@@ -70,6 +97,11 @@ impl fmt::Display for NotifyError {
             NotifyError::IOEncoding(e) => write!(f, "IOEncoding: {}", e),
             NotifyError::ClientBuild => write!(f, "ClientBuild"),
             NotifyError::ProxyNotRunning => write!(f, "ProxyNotRunning"),
+            NotifyError::WebhookConfig => write!(f, "WebhookConfig"),
+            NotifyError::MqttPublish(e) => write!(f, "MqttPublish: {}", e),
+            NotifyError::Template(e) => write!(f, "Template: {}", e),
+            NotifyError::ServerConfig => write!(f, "ServerConfig"),
+            NotifyError::Server(e) => write!(f, "Server: {}", e),
         }
     }
 }
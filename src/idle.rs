@@ -0,0 +1,129 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{
+    config::{get_imap, write_config, ApplicationConfig},
+    mail::{self, Email, MailCursor},
+    product::Product,
+    Notifier, NotifyError,
+};
+
+const DEFAULT_POLL_INTERVAL: u64 = 30;
+// How long to wait before connecting again after losing the connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// Watches the inbox on its own connection, so mail is handled as it arrives rather than once a cycle
+pub struct MailListener {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    // Only used when the server doesn't support IDLE
+    poll_interval: Duration,
+}
+
+impl MailListener {
+    pub fn from_config(application_config: &ApplicationConfig) -> Option<Self> {
+        if !application_config.has_mail_listener() {
+            return None;
+        }
+
+        Some(MailListener {
+            host: application_config.imap_host.clone()?,
+            port: application_config.imap_port?,
+            username: application_config.imap_username.clone()?,
+            password: application_config.imap_password.clone()?,
+            poll_interval: Duration::from_secs(
+                application_config
+                    .imap_poll_interval
+                    .unwrap_or(DEFAULT_POLL_INTERVAL),
+            ),
+        })
+    }
+
+    // Read mail on a thread of its own, since the IMAP client blocks, and send alerts for it here
+    pub fn spawn(self, notifier: Arc<Mutex<Notifier>>) {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            // Kept across reconnects, so mail that was already handled isn't fetched again
+            let mut cursor = MailCursor::default();
+            loop {
+                match self.watch(&sender, &mut cursor) {
+                    // Nothing's left to handle the mail
+                    Ok(()) => break,
+                    Err(e) => eprintln!(
+                        "Lost the mail connection: {}, reconnecting in {}s",
+                        e,
+                        RECONNECT_DELAY.as_secs()
+                    ),
+                }
+                std::thread::sleep(RECONNECT_DELAY);
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Some(emails) = receiver.recv().await {
+                handle_mail(&notifier, &emails).await;
+            }
+        });
+    }
+
+    // Send new emails along every time the inbox changes, until the connection fails
+    fn watch(
+        &self,
+        sender: &mpsc::UnboundedSender<Vec<Email>>,
+        cursor: &mut MailCursor,
+    ) -> Result<(), NotifyError> {
+        let mut session = get_imap(&self.host, self.port, &self.username, &self.password)?;
+        let idle = session
+            .capabilities()
+            .map(|capabilities| capabilities.has_str("IDLE"))
+            .unwrap_or(false);
+        if idle {
+            println!("Watching {} for mail", self.host);
+        } else {
+            println!(
+                "{} doesn't support IDLE, checking for mail every {}s",
+                self.host,
+                self.poll_interval.as_secs()
+            );
+        }
+
+        loop {
+            let emails = mail::fetch_new(&mut session, cursor)?;
+            if !emails.is_empty() && sender.send(emails).is_err() {
+                // Nothing's left to handle the mail, so we're shutting down
+                return Ok(());
+            }
+
+            if idle {
+                // Returns once the mailbox changes, re-issuing IDLE every 29 minutes so the server doesn't log us out
+                session
+                    .idle()
+                    .and_then(|handle| handle.wait_keepalive())
+                    .map_err(|e| NotifyError::ImapConnection(Box::new(e)))?;
+            } else {
+                std::thread::sleep(self.poll_interval);
+            }
+        }
+    }
+}
+
+// Send alerts for whatever in the mail matches a rule. The notifier isn't locked while they're sent
+async fn handle_mail(notifier: &Mutex<Notifier>, emails: &[Email]) {
+    let matches = notifier.lock().await.match_mail(emails);
+    if matches.products.is_empty() {
+        return;
+    }
+
+    let found = matches.products.iter().cloned().collect::<Vec<Product>>();
+    crate::handle_found_products(notifier, &found).await;
+    // The mail is only marked as seen once its alerts have been planned
+    let mut notifier = notifier.lock().await;
+    notifier.mark_mail_seen(matches);
+    if let Err(e) = write_config(&mut notifier).await {
+        eprintln!("Failed to save the config after handling mail: {}", e);
+    }
+}
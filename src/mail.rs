@@ -1,58 +1,515 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::TcpStream;
+use std::sync::PoisonError;
 
-use chrono::Local;
+use chrono::{DateTime, Local};
+use imap::{types::Fetch, Session};
+use mailparse::{MailHeaderMap, ParsedMail};
+use native_tls::TlsStream;
+use regex::{Captures, Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 
 use crate::error::NotifyError;
-use crate::product::Product;
+use crate::product::{Product, ProductDetails};
 use crate::Notifier;
 
-pub async fn get_providers_from_mail(
-    notifier: &mut Notifier,
-) -> Result<HashSet<Product>, NotifyError> {
-    // If we have an Imap session configured
-    let messages = if let Some(imap) = notifier.imap.as_mut() {
-        // Select the inbox
-        let mailbox = imap.select("INBOX").map_err(|_| NotifyError::MailboxLoad)?;
-
-        // Create a sequence set of the last 50 messages. (Format 1,2,3,4,5...)
-        let selected = (mailbox.exists - 50..mailbox.exists)
-            .map(|n| n.to_string())
-            .collect::<Vec<String>>()
-            .join(",");
-
-        // Fetch the messages from the sequence set with the properties listed. Check the IMAP RFC for more info: https://tools.ietf.org/html/rfc3501#page-54
-        imap.fetch(
-            selected,
-            "(ENVELOPE BODY[] FLAGS INTERNALDATE BODY[HEADER])",
-        )
-        .map_err(|_| NotifyError::EmailFetch)?
-    } else {
-        return Ok(HashSet::new());
+// How many of the newest emails are checked each cycle
+const CHECKED_MESSAGES: u32 = 50;
+
+// Turns "notify me" emails from a retailer into product alerts
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MailRule {
+    // Identifies the rule in `mail_last_seen`, so renaming it sends old emails again
+    pub name: String,
+    // One of the provider keys, like "bestbuy"
+    pub provider: String,
+    // Matched case insensitively against the From header, an address or part of one
+    pub sender: Option<String>,
+    // Case insensitive regexes that must match the subject and text of the email
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    // Regex finding the product link in the email, taken from its `url` group, or the whole match
+    // Without one only evga and newegg work, as a generic "has new products" alert
+    pub url: Option<String>,
+    // The product name, otherwise a `product` group from the subject or body regex, otherwise the subject
+    pub product: Option<String>,
+    pub catalog_id: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+// A rule with its regexes built, made once when the notifier starts
+pub struct MailMatcher {
+    rule: MailRule,
+    subject: Option<Regex>,
+    body: Option<Regex>,
+    url: Option<Regex>,
+}
+
+// What we used to do before rules, kept for configs without any
+fn default_rules() -> Vec<MailRule> {
+    ["evga", "newegg"]
+        .iter()
+        .map(|provider| MailRule {
+            name: provider.to_string(),
+            provider: provider.to_string(),
+            sender: None,
+            subject: Some(provider.to_string()),
+            body: None,
+            url: None,
+            product: None,
+            catalog_id: None,
+            tags: None,
+        })
+        .collect()
+}
+
+pub fn build_matchers(rules: Option<&[MailRule]>) -> Result<Vec<MailMatcher>, NotifyError> {
+    let rules = match rules {
+        Some(rules) => rules.to_vec(),
+        None => default_rules(),
     };
 
-    // For each of the messages, check if the email contains keywords we want to see, assuming the email was received after the last previously checked one.
-    let set = messages
+    rules
         .into_iter()
-        .filter_map(|f| {
-            let body = f.envelope()?;
-            let subject = body.subject?;
-            let subject = String::from_utf8(subject.to_vec()).ok()?;
-            let subject = subject.to_ascii_lowercase();
-            let date = f.internal_date()?;
-            if subject.contains("evga") && date > notifier.config.application_config.last_seen_evga
-            {
-                notifier.config.application_config.last_seen_evga = Local::now();
-                Some(Product::Evga(None))
-            } else if subject.contains("newegg")
-                && date > notifier.config.application_config.last_seen_newegg
-            {
-                notifier.config.application_config.last_seen_newegg = Local::now();
-                Some(Product::NewEgg(None))
-            } else {
-                None
+        .map(|rule| {
+            if !Product::KEYS.contains(&rule.provider.as_str()) {
+                return Err(NotifyError::MailRule(format!(
+                    "{} has unknown provider {}",
+                    rule.name, rule.provider
+                )));
+            }
+            if rule.url.is_none() && !["evga", "newegg"].contains(&rule.provider.as_str()) {
+                return Err(NotifyError::MailRule(format!(
+                    "{} needs a url regex to alert for {}",
+                    rule.name, rule.provider
+                )));
             }
+
+            let build = |pattern: &Option<String>| {
+                pattern
+                    .as_ref()
+                    .map(|pattern| {
+                        RegexBuilder::new(pattern)
+                            .case_insensitive(true)
+                            .build()
+                            .map_err(|e| NotifyError::MailRule(format!("{}: {}", rule.name, e)))
+                    })
+                    .transpose()
+            };
+            Ok(MailMatcher {
+                subject: build(&rule.subject)?,
+                body: build(&rule.body)?,
+                url: build(&rule.url)?,
+                rule,
+            })
         })
-        .collect::<HashSet<Product>>();
+        .collect()
+}
+
+// The parts of an email rules look at
+pub struct Email {
+    from: String,
+    subject: String,
+    body: String,
+    date: DateTime<Local>,
+}
+
+impl Email {
+    fn from_fetch(message: &Fetch) -> Option<Self> {
+        let date = message.internal_date()?.with_timezone(&Local);
+        Email::parse(message.body()?, date)
+    }
+
+    fn parse(raw: &[u8], date: DateTime<Local>) -> Option<Self> {
+        let mail = mailparse::parse_mail(raw).ok()?;
+        Some(Email {
+            from: mail.headers.get_first_value("From").unwrap_or_default(),
+            subject: mail.headers.get_first_value("Subject").unwrap_or_default(),
+            body: text_parts(&mail).join("\n"),
+            date,
+        })
+    }
+}
+
+// Every text part of the email, decoded. HTML is kept as is so links in it can be found
+fn text_parts(mail: &ParsedMail) -> Vec<String> {
+    if mail.subparts.is_empty() {
+        if mail.ctype.mimetype.starts_with("text/") {
+            return mail.get_body().ok().into_iter().collect();
+        }
+        return vec![];
+    }
+    mail.subparts.iter().flat_map(text_parts).collect()
+}
+
+impl MailMatcher {
+    fn product(&self, email: &Email) -> Option<Product> {
+        if let Some(sender) = &self.rule.sender {
+            if !email.from.to_lowercase().contains(&sender.to_lowercase()) {
+                return None;
+            }
+        }
+        let subject = matches(&self.subject, &email.subject)?;
+        let body = matches(&self.body, &email.body)?;
+
+        let details = match &self.url {
+            Some(url) => {
+                let captures = url.captures(&email.body)?;
+                let page = captures
+                    .name("url")
+                    .or_else(|| captures.get(0))?
+                    .as_str()
+                    // Links pulled out of HTML still have their entities escaped
+                    .replace("&amp;", "&");
+                let product = self
+                    .rule
+                    .product
+                    .clone()
+                    .or_else(|| named(&subject, "product"))
+                    .or_else(|| named(&body, "product"))
+                    .unwrap_or_else(|| email.subject.trim().to_string());
+                Some(ProductDetails {
+                    product,
+                    page,
+                    catalog_id: self.rule.catalog_id.clone(),
+                    tags: self.rule.tags.clone(),
+                    ..ProductDetails::default()
+                })
+            }
+            None => None,
+        };
+
+        Product::from_key(&self.rule.provider, details)
+    }
+}
+
+// Whether the text matches, with what it captured. No regex matches anything
+fn matches<'t>(regex: &Option<Regex>, text: &'t str) -> Option<Option<Captures<'t>>> {
+    match regex {
+        Some(regex) => regex.captures(text).map(Some),
+        None => Some(None),
+    }
+}
+
+fn named(captures: &Option<Captures>, name: &str) -> Option<String> {
+    Some(captures.as_ref()?.name(name)?.as_str().trim().to_string())
+}
+
+pub async fn get_providers_from_mail(
+    notifier: &Mutex<Notifier>,
+) -> Result<Option<MailMatches>, NotifyError> {
+    // If we have an Imap session configured, and the listener isn't already watching for mail
+    // It's taken out while we fetch, so the notifier isn't locked while we wait on the server
+    let mut imap = {
+        let mut notifier = notifier.lock().await;
+        if notifier.config.application_config.has_mail_listener() {
+            return Ok(None);
+        }
+        match notifier.imap.take() {
+            Some(imap) => imap,
+            None => return Ok(None),
+        }
+    };
+
+    let emails = fetch_recent(imap.get_mut().unwrap_or_else(PoisonError::into_inner));
+    let mut notifier = notifier.lock().await;
+    notifier.imap = Some(imap);
+    Ok(Some(notifier.match_mail(&emails?)))
+}
+
+// Read the newest emails in the inbox without marking them as read
+pub fn fetch_recent(imap: &mut Session<TlsStream<TcpStream>>) -> Result<Vec<Email>, NotifyError> {
+    // Select the inbox
+    let mailbox = imap.select("INBOX").map_err(|_| NotifyError::MailboxLoad)?;
+    if mailbox.exists == 0 {
+        return Ok(vec![]);
+    }
+
+    // Create a sequence set of the newest messages. (Format 1,2,3,4,5...)
+    let selected = (mailbox.exists.saturating_sub(CHECKED_MESSAGES - 1).max(1)..=mailbox.exists)
+        .map(|n| n.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+
+    // Fetch the messages from the sequence set with the properties listed. Check the IMAP RFC for more info: https://tools.ietf.org/html/rfc3501#page-54
+    let messages = imap
+        .fetch(selected, "(BODY.PEEK[] INTERNALDATE)")
+        .map_err(|_| NotifyError::EmailFetch)?;
+
+    Ok(messages.iter().filter_map(Email::from_fetch).collect())
+}
+
+// Where the mail listener has read up to, so each time the inbox changes only what's new is fetched
+#[derive(Default)]
+pub struct MailCursor {
+    uid_validity: Option<u32>,
+    last_uid: Option<u32>,
+}
+
+// Read the emails that arrived since the cursor without marking them as read
+// With nothing read yet, or once the server renumbers the inbox, that's the newest CHECKED_MESSAGES
+pub fn fetch_new(
+    imap: &mut Session<TlsStream<TcpStream>>,
+    cursor: &mut MailCursor,
+) -> Result<Vec<Email>, NotifyError> {
+    let mailbox = imap.select("INBOX").map_err(|_| NotifyError::MailboxLoad)?;
+    if mailbox.uid_validity != cursor.uid_validity {
+        cursor.uid_validity = mailbox.uid_validity;
+        cursor.last_uid = None;
+    }
+
+    let uids = match cursor.last_uid {
+        // "n:*" always includes the newest message, even when it's older than n
+        Some(last_uid) => imap
+            .uid_search(format!("UID {}:*", last_uid + 1))
+            .map_err(|_| NotifyError::EmailFetch)?
+            .into_iter()
+            .filter(|uid| *uid > last_uid)
+            .collect::<Vec<u32>>(),
+        None if mailbox.exists == 0 => vec![],
+        None => {
+            let newest = format!(
+                "{}:{}",
+                mailbox.exists.saturating_sub(CHECKED_MESSAGES - 1).max(1),
+                mailbox.exists
+            );
+            imap.fetch(newest, "UID")
+                .map_err(|_| NotifyError::EmailFetch)?
+                .iter()
+                .filter_map(|message| message.uid)
+                .collect()
+        }
+    };
+    if uids.is_empty() {
+        // Start from whatever comes next, rather than the newest messages again
+        cursor.last_uid = cursor
+            .last_uid
+            .or_else(|| mailbox.uid_next.map(|next| next.saturating_sub(1)));
+        return Ok(vec![]);
+    }
+
+    let uid_set = uids
+        .iter()
+        .map(|uid| uid.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let messages = imap
+        .uid_fetch(uid_set, "(UID BODY.PEEK[] INTERNALDATE)")
+        .map_err(|_| NotifyError::EmailFetch)?;
+    cursor.last_uid = uids.into_iter().max().max(cursor.last_uid);
+
+    Ok(messages.iter().filter_map(Email::from_fetch).collect())
+}
+
+// What was found in the mail, and the newest email each rule matched
+pub struct MailMatches {
+    pub products: HashSet<Product>,
+    seen: HashMap<String, DateTime<Local>>,
+}
+
+impl Notifier {
+    // The products in emails that came in since each rule last matched one
+    // Rules aren't moved past them until `mark_mail_seen`, once the alerts for them have been planned
+    pub fn match_mail(&mut self, emails: &[Email]) -> MailMatches {
+        let application_config = &mut self.config.application_config;
+        let legacy_last_seen = [
+            ("evga", application_config.last_seen_evga),
+            ("newegg", application_config.last_seen_newegg),
+        ];
+        let last_seen = application_config
+            .mail_last_seen
+            .get_or_insert_with(HashMap::new);
+
+        let mut products = HashSet::new();
+        let mut seen = HashMap::new();
+        for matcher in &self.mail_rules {
+            let name = &matcher.rule.name;
+            // New rules start from now rather than alerting for everything already in the inbox
+            let since = *last_seen.entry(name.clone()).or_insert_with(|| {
+                legacy_last_seen
+                    .iter()
+                    .find(|(provider, _)| provider == name)
+                    .map(|(_, seen)| *seen)
+                    .unwrap_or_else(Local::now)
+            });
+
+            for email in emails.iter().filter(|email| email.date > since) {
+                if let Some(product) = matcher.product(email) {
+                    println!("Email \"{}\" matched mail rule {}", email.subject, name);
+                    products.insert(product);
+                    let seen = seen.entry(name.clone()).or_insert(since);
+                    if email.date > *seen {
+                        *seen = email.date;
+                    }
+                }
+            }
+        }
+
+        MailMatches { products, seen }
+    }
+
+    // Move each rule past the emails it matched, so they aren't sent again
+    pub fn mark_mail_seen(&mut self, matches: MailMatches) {
+        let last_seen = self
+            .config
+            .application_config
+            .mail_last_seen
+            .get_or_insert_with(HashMap::new);
+        for (name, date) in matches.seen {
+            let seen = last_seen.entry(name).or_insert(date);
+            if date > *seen {
+                *seen = date;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+
+    // A restock email as a retailer sends it, with the link only in the HTML part
+    const BESTBUY_EMAIL: &str = "From: Best Buy <BestBuyInfo@emailinfo.bestbuy.com>\r
+To: me@example.com\r
+Subject: It's back: NVIDIA GeForce RTX 3080 Founders Edition\r
+Date: Tue, 1 Jun 2021 09:30:00 -0700\r
+MIME-Version: 1.0\r
+Content-Type: multipart/alternative; boundary=\"restock\"\r
+\r
+--restock\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+Good news, an item you asked about is available.\r
+--restock\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<p>Good news!</p><a href=\"https://www.bestbuy.com/site/6429440.p?skuId=6429440&amp;ref=email\">Shop now</a>\r
+--restock--\r
+";
+
+    const EVGA_EMAIL: &str = "From: EVGA <noreply@evga.com>\r
+Subject: EVGA Notify: product available\r
+Content-Type: text/plain\r
+\r
+The item you requested is now in stock.\r
+";
+
+    fn date(rfc3339: &str) -> DateTime<Local> {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    fn notifier(rules: serde_json::Value) -> Notifier {
+        let mut notifier = stub::notifier(
+            serde_json::json!({
+                "mail_rules": rules,
+                "mail_last_seen": { "bestbuy": "2021-06-01T00:00:00-07:00" },
+            }),
+            serde_json::json!([]),
+        );
+        notifier.mail_rules =
+            build_matchers(notifier.config.application_config.mail_rules.as_deref()).unwrap();
+        notifier
+    }
+
+    fn bestbuy_rule() -> serde_json::Value {
+        serde_json::json!([{
+            "name": "bestbuy",
+            "provider": "bestbuy",
+            "sender": "bestbuy.com",
+            "subject": "it's back: (?P<product>.+)",
+            "url": "https://www\\.bestbuy\\.com/site/[^\"]+",
+            "tags": ["fe"],
+        }])
+    }
+
+    #[test]
+    fn rules_pull_the_product_out_of_the_email() {
+        let mut notifier = notifier(bestbuy_rule());
+        let email =
+            Email::parse(BESTBUY_EMAIL.as_bytes(), date("2021-06-01T09:30:00-07:00")).unwrap();
+
+        let matches = notifier.match_mail(&[email]);
+        let found = matches.products.iter().cloned().collect::<Vec<Product>>();
+        // Nothing's marked as seen until the alert has been planned
+        let last_seen = |notifier: &Notifier| {
+            notifier
+                .config
+                .application_config
+                .mail_last_seen
+                .as_ref()
+                .and_then(|seen| seen.get("bestbuy").cloned())
+        };
+        assert_ne!(
+            last_seen(&notifier),
+            Some(date("2021-06-01T09:30:00-07:00"))
+        );
+        notifier.mark_mail_seen(matches);
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].get_name().unwrap(),
+            "NVIDIA GeForce RTX 3080 Founders Edition"
+        );
+        assert_eq!(
+            found[0].get_url().unwrap(),
+            "https://www.bestbuy.com/site/6429440.p?skuId=6429440&ref=email"
+        );
+        assert_eq!(found[0].get_tags(), &["fe".to_string()]);
+        assert_eq!(
+            last_seen(&notifier),
+            Some(date("2021-06-01T09:30:00-07:00"))
+        );
+    }
+
+    #[test]
+    fn mail_from_elsewhere_or_already_seen_is_ignored() {
+        let mut notifier = notifier(bestbuy_rule());
+        let spoofed =
+            BESTBUY_EMAIL.replace("BestBuyInfo@emailinfo.bestbuy.com", "deals@example.com");
+        let spoofed = Email::parse(spoofed.as_bytes(), date("2021-06-01T09:30:00-07:00")).unwrap();
+        let old =
+            Email::parse(BESTBUY_EMAIL.as_bytes(), date("2021-05-31T09:30:00-07:00")).unwrap();
+
+        assert!(notifier.match_mail(&[spoofed, old]).products.is_empty());
+    }
+
+    #[test]
+    fn configs_without_rules_still_alert_for_evga_and_newegg() {
+        let mut notifier = stub::notifier(serde_json::json!({}), serde_json::json!([]));
+        notifier.mail_rules = build_matchers(None).unwrap();
+        notifier.config.application_config.last_seen_evga = date("2021-06-01T00:00:00-07:00");
+        let email = Email::parse(EVGA_EMAIL.as_bytes(), date("2021-06-01T09:30:00-07:00")).unwrap();
+
+        let found = notifier
+            .match_mail(&[email])
+            .products
+            .into_iter()
+            .collect::<Vec<Product>>();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].to_key(), "evga");
+    }
+
+    #[test]
+    fn rules_need_a_known_provider_and_a_way_to_find_the_link() {
+        let rule = |provider: &str, url: Option<&str>| MailRule {
+            name: "rule".to_string(),
+            provider: provider.to_string(),
+            sender: None,
+            subject: None,
+            body: None,
+            url: url.map(str::to_string),
+            product: None,
+            catalog_id: None,
+            tags: None,
+        };
 
-    Ok(set)
+        assert!(build_matchers(Some(&[rule("nowhere", Some("https://.+"))])).is_err());
+        assert!(build_matchers(Some(&[rule("bestbuy", None)])).is_err());
+        assert!(build_matchers(Some(&[rule("bestbuy", Some("https://.+"))])).is_ok());
+    }
 }
@@ -1,45 +1,81 @@
 #![feature(async_closure)]
 
 use std::net::TcpStream;
+use std::sync::Arc;
 
 use chrono::{Duration, Local};
 use native_tls::{self, TlsStream};
-use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 
 use config::*;
 use error::NotifyError;
-use product::Product;
+use notifier::{
+    budget::SpendLedger,
+    discord::DiscordTarget,
+    template::Channel,
+    twilio::{TextChannel, TextRecord},
+    Alert, DeliveryFailure, SubscriberDelivery,
+};
+use product::{Offer, Product, ProductDetails};
+use subscriber::{QuietMode, Subscriber};
 
+mod catalog;
+mod claim;
+mod commands;
 mod config;
 mod error;
+mod idle;
 mod mail;
 mod notifier;
 mod product;
 mod scraping;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Subscriber {
-    service: Vec<String>,
-    to_phone_number: String,
-    active: bool,
-}
+mod server;
+mod stats;
+#[cfg(test)]
+mod stub;
+mod subscriber;
 
 pub struct Notifier {
-    pub twilio: Option<twilio::Client>,
-    pub imap: Option<imap::Session<TlsStream<TcpStream>>>,
+    pub twilio: Option<notifier::twilio::Client>,
+    // Locked so the notifier can be shared with the mail listener, the session isn't Sync on its own
+    pub imap: Option<std::sync::Mutex<imap::Session<TlsStream<TcpStream>>>>,
+    pub smtp: Option<lettre::SmtpTransport>,
+    pub mqtt: Option<notifier::mqtt::MqttPublisher>,
+    // Built from `mail_rules` in the config
+    pub mail_rules: Vec<mail::MailMatcher>,
+    // Deliveries we gave up on this cycle
+    pub delivery_failures: Vec<DeliveryFailure>,
+    // Shared with every sender, so texts and calls going out at once can't go over the budget together
+    pub spend_ledger: Arc<std::sync::Mutex<SpendLedger>>,
     pub config: Config,
 }
 
+// What `plan_alerts` worked out to send, for `send_alerts` to send without the lock
+pub struct Outgoing {
+    alerts: Vec<Alert>,
+    // Each subscriber with their message, the claim links that follow it and the alerts in it
+    subscribers: Vec<(Subscriber, String, String, Vec<Alert>)>,
+    // Whether texts can go out, or are waiting out the cooldown
+    texts: bool,
+}
+
 impl Notifier {
-    pub fn active_subscribers(&self, key: &str) -> Vec<&Subscriber> {
+    pub fn active_subscribers(&self, product: &Product) -> Vec<&Subscriber> {
         self.config
             .subscribers
             .iter()
-            // Filter the subscribers to only active subscribers that are subscribed to this provider
-            .filter(|subscriber| subscriber.active && subscriber.service.contains(&key.to_string()))
+            .filter(|subscriber| {
+                subscriber.should_notify(product)
+                    && self.claim_for(Some(&subscriber.id()), product).is_none()
+            })
             .collect::<Vec<&Subscriber>>()
     }
 
+    // Whether a message to the subscriber goes out as a text too, which the cooldown applies to
+    pub fn will_text(&self, subscriber: &Subscriber) -> bool {
+        self.twilio.is_some() && subscriber.can_be_texted()
+    }
+
     pub fn get_from_phone_number(&self) -> Option<&String> {
         self.config.application_config.from_phone_number.as_ref()
     }
@@ -65,53 +101,659 @@ impl Notifier {
         }
     }
 
-    pub async fn handle_found_product(&mut self, product: &Product) -> Result<(), NotifyError> {
+    // A copy to send with once the lock is released, so texts to the server and claim pages aren't held up by the network
+    // It has the clients and config but not the mail session, and anything recorded on it is thrown away
+    pub fn sender(&self) -> Notifier {
+        Notifier {
+            twilio: self.twilio.clone(),
+            imap: None,
+            smtp: self.smtp.clone(),
+            mqtt: self.mqtt.clone(),
+            mail_rules: vec![],
+            delivery_failures: vec![],
+            spend_ledger: self.spend_ledger.clone(),
+            config: self.config.clone(),
+        }
+    }
+
+    // Work out what to send for everything found this cycle, so each channel and subscriber gets one combined message
+    // The claim links, held back digests and Discord messages that go with it are recorded here, the rest is sent by `send_alerts`
+    pub async fn plan_alerts(&mut self, products: &[Product]) -> Option<Outgoing> {
+        // Nobody hears about what the team already has
+        let products = products
+            .iter()
+            .filter(|product| match self.claim_for(None, product) {
+                Some(claim) => {
+                    println!("Not sending {}", claim.describe());
+                    false
+                }
+                None => true,
+            })
+            .collect::<Vec<&Product>>();
+        if products.is_empty() {
+            return None;
+        }
+
         // If the notifier is configured to open this in a browser
         if self.config.application_config.should_open_browser() {
-            // Open the page in a browser
-            product.open_in_browser()?;
+            for product in &products {
+                // Open the page in a browser
+                if let Err(e) = product.open_in_browser() {
+                    eprintln!("Couldn't open {:?} in a browser: {}", product, e);
+                }
+            }
+        }
+
+        // Only texts wait out the cooldown, so a listing flapping in and out of stock can't run up a burst of them
+        let texts = self.config.application_config.should_send_notification();
+
+        let mut alerts = products
+            .iter()
+            .map(|product| self.build_alert(product))
+            .collect::<Vec<Alert>>();
+        // Keep the evidence, the page may have changed by the time anyone looks
+        for alert in &mut alerts {
+            alert.snapshot_path = self.save_snapshot(alert).await;
+        }
+
+        self.queue_broadcast(&alerts.iter().collect::<Vec<&Alert>>());
+
+        // Group what was found by who wants it
+        let mut wanted: Vec<(Subscriber, Vec<&Alert>)> = vec![];
+        for alert in &alerts {
+            for subscriber in self.active_subscribers(&alert.product) {
+                match wanted.iter_mut().find(|(s, _)| s.id() == subscriber.id()) {
+                    Some((_, subscriber_alerts)) => subscriber_alerts.push(alert),
+                    None => wanted.push((subscriber.clone(), vec![alert])),
+                }
+            }
         }
 
-        if let Some(discord_url) = &self.config.application_config.discord_url {
-            notifier::discord::send_webhook(product, discord_url).await?
+        let mut subscribers = vec![];
+        for (subscriber, subscriber_alerts) in &wanted {
+            let mut to_send = vec![];
+            for alert in subscriber_alerts {
+                // Hold off on anyone who is asleep, unless this is something they asked to be woken up for
+                match subscriber.quiet_mode() {
+                    Some(mode) if !subscriber.overrides_quiet_hours(&alert.product) => {
+                        if mode == QuietMode::Digest {
+                            self.defer_message(subscriber, &alert.summary);
+                        }
+                        println!("Quiet hours for {}, {:?} message", subscriber.id(), mode);
+                    }
+                    _ => to_send.push(*alert),
+                }
+            }
+
+            if to_send.is_empty() {
+                continue;
+            }
+
+            let message = notifier::combined_message(&to_send);
+            // Give them a way to say they got it, kept apart so templates can't drop it
+            let mut claims = String::new();
+            for alert in &to_send {
+                if let Some(link) = self.claim_link(subscriber, alert) {
+                    claims.push_str(&match to_send.len() {
+                        1 => format!("\n\nBought it? {}", link),
+                        _ => format!(
+                            "\n\nBought {}? {}",
+                            alert.product.get_name().unwrap_or("it"),
+                            link
+                        ),
+                    });
+                }
+            }
+            subscribers.push((
+                subscriber.clone(),
+                message,
+                claims,
+                to_send.into_iter().cloned().collect(),
+            ));
         }
 
-        if self.config.application_config.has_twilio_config()
-            && self.config.application_config.should_send_notification()
+        if subscribers
+            .iter()
+            .any(|(subscriber, ..)| self.will_text(subscriber))
         {
-            let subscribers = self.active_subscribers(product.to_key());
-            let client = self.twilio.as_ref().unwrap();
-            for subscriber in subscribers {
-                notifier::twilio::send_twilio_message(
-                    product,
-                    client,
-                    subscriber,
-                    self.config
-                        .application_config
-                        .from_phone_number
+            if texts {
+                // Set now rather than once they're sent, so mail coming in meanwhile waits it out too
+                self.config.application_config.last_notification_sent = Local::now();
+            } else {
+                println!("Not texting anyone, a text went out in the last 30 minutes");
+            }
+        }
+
+        Some(Outgoing {
+            alerts,
+            subscribers,
+            texts,
+        })
+    }
+
+    // Send what `plan_alerts` worked out. Nothing is recorded, so a `sender` can do it without the lock
+    pub async fn send_alerts(&self, outgoing: &Outgoing) -> Vec<SubscriberDelivery> {
+        let all = outgoing.alerts.iter().collect::<Vec<&Alert>>();
+        // Reach everyone at once, so one subscriber's failures don't hold up or stop anyone else's
+        // The channels everyone shares go out alongside them, so a slow one doesn't delay the texts
+        let subscribers = futures::future::join_all(outgoing.subscribers.iter().map(
+            |(subscriber, message, claims, alerts)| async move {
+                let alerts = alerts.iter().collect::<Vec<&Alert>>();
+                self.notify_subscriber(subscriber, message, claims, &alerts, outgoing.texts)
+                    .await
+            },
+        ));
+        let ((), deliveries) = futures::future::join(self.broadcast(&all), subscribers).await;
+        deliveries
+    }
+
+    // Keep track of what `send_alerts` got through to everyone
+    pub fn record_alerts(&mut self, outgoing: &Outgoing, deliveries: Vec<SubscriberDelivery>) {
+        for ((subscriber, _, _, alerts), delivery) in outgoing.subscribers.iter().zip(deliveries) {
+            let texted = delivery.texts.iter().any(|text| text.sid.is_some());
+            self.record_delivery(delivery);
+            // Call anyone who doesn't acknowledge a high priority alert in time, by texting back ACK
+            // Only once a text went out, otherwise there's nothing for them to have seen or acknowledge
+            if texted {
+                self.queue_escalation(subscriber, &alerts.iter().collect::<Vec<&Alert>>());
+            }
+        }
+    }
+
+    // Queue the Discord messages for everything found, they're sent by `discord::send_queued`
+    fn queue_broadcast(&mut self, all: &[&Alert]) {
+        let application_config = &self.config.application_config;
+        let discord_targets = application_config
+            .discord_url
+            .iter()
+            .map(|url| DiscordTarget::unfiltered(url))
+            .chain(application_config.discord_targets.iter().flatten().cloned())
+            .collect::<Vec<DiscordTarget>>();
+        if discord_targets.is_empty() {
+            return;
+        }
+
+        let discord_alerts = self.channel_alerts(Channel::Discord, self.locale(None), all);
+        let mut webhooks = vec![];
+        for target in &discord_targets {
+            // Route each product only to the channels that want it
+            let routed = discord_alerts
+                .iter()
+                .filter(|alert| target.accepts(&alert.product))
+                .collect::<Vec<&Alert>>();
+            if routed.is_empty() {
+                continue;
+            }
+
+            webhooks.extend(notifier::discord::build_webhooks(
+                &routed,
+                &target.url,
+                target
+                    .avatar_url
+                    .as_deref()
+                    .or(application_config.discord_avatar_url.as_deref()),
+                target
+                    .mentions
+                    .as_ref()
+                    .or(application_config.discord_mentions.as_ref()),
+            ));
+        }
+        self.queue_discord_webhooks(webhooks);
+    }
+
+    // Send to every other channel that isn't tied to a subscriber
+    async fn broadcast(&self, all: &[&Alert]) {
+        let locale = self.locale(None);
+
+        if let Some(slack_urls) = &self.config.application_config.slack_urls {
+            let slack_alerts = self.channel_alerts(Channel::Slack, locale, all);
+            let slack_alerts = slack_alerts.iter().collect::<Vec<&Alert>>();
+            for slack_url in slack_urls {
+                if let Err(e) = notifier::slack::send_webhook(&slack_alerts, slack_url).await {
+                    eprintln!("Slack webhook failed: {}", e);
+                }
+            }
+        }
+
+        for webhook in self.config.application_config.webhooks.iter().flatten() {
+            if let Err(e) = notifier::webhook::send_webhook(all, webhook).await {
+                eprintln!("Webhook to {} failed: {}", webhook.url, e);
+            }
+        }
+
+        if let Some(token) = &self.config.application_config.telegram_bot_token {
+            let (message, telegram_alerts) = self.channel_message(
+                Channel::Telegram,
+                locale,
+                &notifier::combined_message(all),
+                all,
+            );
+            let telegram_alerts = telegram_alerts.iter().collect::<Vec<&Alert>>();
+            for chat_id in self
+                .config
+                .application_config
+                .telegram_chat_ids
+                .iter()
+                .flatten()
+            {
+                if let Err(e) = notifier::telegram::send_message(
+                    self.config.application_config.telegram_api_url(),
+                    token,
+                    *chat_id,
+                    &message,
+                    &telegram_alerts,
+                )
+                .await
+                {
+                    eprintln!("Telegram message to {} failed: {}", chat_id, e);
+                }
+            }
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            if let Err(e) = mqtt.publish_restocks(all).await {
+                eprintln!("MQTT restock event failed: {}", e);
+            }
+        }
+    }
+
+    // Send a message through every channel the subscriber can be reached on, with `extras` after it
+    // A channel failing doesn't stop the rest, everything that happened is returned to be recorded
+    async fn notify_subscriber(
+        &self,
+        subscriber: &Subscriber,
+        message: &str,
+        extras: &str,
+        alerts: &[&Alert],
+        texts_allowed: bool,
+    ) -> SubscriberDelivery {
+        let application_config = &self.config.application_config;
+        let locale = self.locale(Some(subscriber));
+        let mut delivery = SubscriberDelivery {
+            subscriber_id: subscriber.id(),
+            ..SubscriberDelivery::default()
+        };
+        let mut fail = |channel: Channel, error: NotifyError| {
+            delivery.failures.push(DeliveryFailure::new(
+                channel,
+                subscriber.id(),
+                alerts,
+                error,
+            ))
+        };
+
+        let mut texts = vec![];
+        let mut reserved = 0.0;
+        if let (Some(client), Some(from_phone), Some(to_phone)) = (
+            &self.twilio,
+            &application_config.from_phone_number,
+            &subscriber.to_phone_number,
+        ) {
+            if let Some(reason) = &subscriber.sms_disabled {
+                println!(
+                    "Not texting {}, texts were turned off: {}",
+                    to_phone, reason
+                );
+            } else if !texts_allowed {
+                println!(
+                    "Not texting {}, a text went out in the last 30 minutes",
+                    to_phone
+                );
+            } else {
+                let (mut message, _) = self.channel_message(Channel::Sms, locale, message, alerts);
+                message.push_str(extras);
+                let channel = subscriber.text_channel.unwrap_or_default();
+                let from_phone = match channel {
+                    TextChannel::Whatsapp => application_config
+                        .whatsapp_from_number
                         .as_ref()
-                        .unwrap(),
+                        .unwrap_or(from_phone),
+                    TextChannel::Sms | TextChannel::Mms => from_phone,
+                };
+                let media_urls = match channel {
+                    TextChannel::Mms => alerts
+                        .iter()
+                        .filter_map(|alert| alert.product.get_offer()?.image_url.as_deref())
+                        .collect(),
+                    TextChannel::Sms | TextChannel::Whatsapp => vec![],
+                };
+                let max_length = application_config.text_max_length(channel);
+                // Everyone is texted at once, so what it'll cost is set aside before it's sent
+                let cost = self.estimate_text_cost(
+                    channel,
+                    &notifier::twilio::split_text(&message, max_length),
+                );
+                if !self.reserve_spend(cost) {
+                    println!("Not texting {}, the Twilio budget has been spent", to_phone);
+                } else {
+                    reserved += cost;
+                    let (sent, error) = match notifier::twilio::send_twilio_message(
+                        &message,
+                        client,
+                        &channel.address(to_phone),
+                        &channel.address(from_phone),
+                        max_length,
+                        &media_urls,
+                    )
+                    .await
+                    {
+                        Ok(sent) => (sent, None),
+                        Err(e) => (e.sent, Some(e.error)),
+                    };
+                    // Parts that went out before one failed still count
+                    texts.extend(sent.into_iter().enumerate().map(|(i, resource)| {
+                        TextRecord::sent(to_phone, channel.billed_as(i), resource)
+                    }));
+                    if let Some(e) = error {
+                        texts.push(TextRecord::failed(to_phone, &e));
+                        fail(Channel::Sms, e);
+                    }
+                }
+            }
+        }
+
+        if let (Some(token), Some(chat_id)) = (
+            &application_config.telegram_bot_token,
+            subscriber.telegram_chat_id,
+        ) {
+            let (mut message, alerts) =
+                self.channel_message(Channel::Telegram, locale, message, alerts);
+            message.push_str(extras);
+            if let Err(e) = notifier::telegram::send_message(
+                application_config.telegram_api_url(),
+                token,
+                chat_id,
+                &message,
+                &alerts.iter().collect::<Vec<&Alert>>(),
+            )
+            .await
+            {
+                fail(Channel::Telegram, e);
+            }
+        }
+
+        if let (Some(transport), Some(from), Some(to)) = (
+            &self.smtp,
+            &application_config.smtp_from_address,
+            &subscriber.email,
+        ) {
+            let templated = self.templated_alerts(Channel::Email, locale, alerts);
+            if let Err(e) = notifier::email::send_email(
+                transport,
+                from,
+                to,
+                message,
+                extras,
+                alerts,
+                templated.as_deref(),
+            )
+            .await
+            {
+                fail(Channel::Email, e);
+            }
+        }
+
+        if let Some(name) = &subscriber.push_target {
+            match application_config
+                .push_targets
+                .as_ref()
+                .and_then(|targets| targets.get(name))
+            {
+                Some(target) => {
+                    let (mut message, push_alerts) =
+                        self.channel_message(Channel::Push, locale, message, alerts);
+                    message.push_str(extras);
+                    if let Err(e) = notifier::push::send_push(
+                        target,
+                        &message,
+                        &push_alerts.iter().collect::<Vec<&Alert>>(),
+                        self.alert_priority(alerts),
+                    )
+                    .await
+                    {
+                        fail(Channel::Push, e);
+                    }
+                }
+                None => eprintln!("No push target named {} for {}", name, subscriber.id()),
+            }
+        }
+
+        delivery.texts = texts;
+        delivery.reserved = reserved;
+        delivery
+    }
+
+    fn defer_message(&mut self, subscriber: &Subscriber, message: &str) {
+        self.config
+            .application_config
+            .deferred_messages
+            .get_or_insert_with(std::collections::HashMap::new)
+            .entry(subscriber.id())
+            .or_default()
+            .push(message.to_string());
+    }
+}
+
+// Send everything found this cycle. The notifier is only locked to work out what to send and to record
+// what happened, never while we wait on the network, so the server can still answer texts meanwhile
+async fn handle_found_products(notifier: &Mutex<Notifier>, products: &[Product]) {
+    let (outgoing, sender) = {
+        let mut notifier = notifier.lock().await;
+        match notifier.plan_alerts(products).await {
+            Some(outgoing) => (outgoing, notifier.sender()),
+            None => return,
+        }
+    };
+
+    let deliveries = sender.send_alerts(&outgoing).await;
+    notifier.lock().await.record_alerts(&outgoing, deliveries);
+    notifier::discord::send_queued(notifier).await;
+}
+
+// Send a made up product through every configured channel, to check credentials and formatting
+// Only the given subscriber is sent to, or every active one without it
+async fn test_notify(
+    notifier: &Mutex<Notifier>,
+    subscriber_id: Option<&str>,
+) -> Result<(), NotifyError> {
+    let (outgoing, sender) = {
+        let mut notifier = notifier.lock().await;
+        let product = Product::BestBuy(ProductDetails {
+            product: "RTX Notifier Test Card".to_string(),
+            page: "https://example.com/rtx-notifier-test".to_string(),
+            tags: Some(vec!["test".to_string()]),
+            ..ProductDetails::default()
+        })
+        .with_offer(Offer {
+            price_cents: Some(69_999),
+            seller: Some("RTX Notifier".to_string()),
+            ..Offer::default()
+        });
+        let alert = notifier.build_alert(&product);
+        notifier.queue_broadcast(&[&alert]);
+
+        let subscribers = notifier
+            .config
+            .subscribers
+            .iter()
+            .filter(|subscriber| match subscriber_id {
+                Some(id) => subscriber.id() == id,
+                None => subscriber.active,
+            })
+            .map(|subscriber| {
+                (
+                    subscriber.clone(),
+                    alert.message.clone(),
+                    String::new(),
+                    vec![alert.clone()],
                 )
-                    .await?;
+            })
+            .collect::<Vec<(Subscriber, String, String, Vec<Alert>)>>();
+        if let (Some(id), true) = (subscriber_id, subscribers.is_empty()) {
+            eprintln!("No subscriber {}", id);
+        }
+
+        let outgoing = Outgoing {
+            alerts: vec![alert],
+            subscribers,
+            texts: true,
+        };
+        (outgoing, notifier.sender())
+    };
+
+    let deliveries = sender.send_alerts(&outgoing).await;
+    notifier::discord::send_queued(notifier).await;
+
+    let mut notifier = notifier.lock().await;
+    for delivery in deliveries {
+        println!(
+            "Sent test to {}, {} channels failed",
+            delivery.subscriber_id,
+            delivery.failures.len()
+        );
+        // Test texts cost the same as real ones, so they count against the budget
+        notifier.record_text_spend(&delivery.subscriber_id, &delivery.texts);
+        notifier.release_spend(delivery.reserved);
+        notifier.delivery_failures.extend(delivery.failures);
+    }
+
+    notifier.report_delivery_failures();
+    write_config(&mut notifier).await
+}
+
+// Send everyone whose quiet hours have ended the messages we held back for them
+async fn send_deferred_digests(notifier: &Mutex<Notifier>) {
+    let (ready, sender) = {
+        let mut notifier = notifier.lock().await;
+        // Anyone who'd be texted waits out the cooldown like any other text, and stays queued until then
+        let texts = notifier
+            .config
+            .application_config
+            .should_send_notification();
+
+        let subscribers = notifier
+            .config
+            .subscribers
+            .iter()
+            .filter(|subscriber| subscriber.active && subscriber.quiet_mode().is_none())
+            .filter(|subscriber| texts || !notifier.will_text(subscriber))
+            .cloned()
+            .collect::<Vec<Subscriber>>();
+        let mut ready = vec![];
+        for subscriber in subscribers {
+            let deferred = notifier
+                .config
+                .application_config
+                .deferred_messages
+                .as_mut();
+            // A dry run leaves them queued for the real thing
+            let messages = if notifier::is_dry_run() {
+                deferred.and_then(|deferred| deferred.get(&subscriber.id()).cloned())
+            } else {
+                deferred.and_then(|deferred| deferred.remove(&subscriber.id()))
+            };
+            match messages {
+                Some(messages) if !messages.is_empty() => ready.push((subscriber, messages)),
+                _ => continue,
             }
         }
+        if ready.is_empty() {
+            return;
+        }
+        if ready
+            .iter()
+            .any(|(subscriber, _)| notifier.will_text(subscriber))
+        {
+            notifier.config.application_config.last_notification_sent = Local::now();
+        }
+        (ready, notifier.sender())
+    };
 
-        Ok(())
+    let mut deliveries = vec![];
+    for (subscriber, messages) in &ready {
+        let digest = format!("While you were away:\n{}", messages.join("\n\n"));
+        deliveries.push(
+            sender
+                .notify_subscriber(subscriber, &digest, "", &[], true)
+                .await,
+        );
+    }
+
+    let mut notifier = notifier.lock().await;
+    for ((subscriber, messages), delivery) in ready.into_iter().zip(deliveries) {
+        if !notifier.record_delivery(delivery) && !notifier::is_dry_run() {
+            // Put them back so they go out next cycle, ahead of anything held back since
+            let queued = notifier
+                .config
+                .application_config
+                .deferred_messages
+                .get_or_insert_with(std::collections::HashMap::new)
+                .entry(subscriber.id())
+                .or_default();
+            let since = std::mem::replace(queued, messages);
+            queued.extend(since);
+        }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), NotifyError> {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    // Set before anything connects, so nothing goes out while the notifier starts up either
+    // The config can turn it on too, which `Notifier::new` does as soon as it's read
+    notifier::set_dry_run(args.iter().any(|arg| arg == "--dry-run"));
     // Get notifier instance and settings
-    let mut notifier = Notifier::new().await?;
+    let notifier = Notifier::new().await?;
+    if notifier::is_dry_run() {
+        println!("Dry run, messages are logged instead of sent");
+    }
+
+    let commands = args
+        .iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+    match commands.as_slice() {
+        [] => {}
+        ["test-notify"] => return test_notify(&Mutex::new(notifier), None).await,
+        ["test-notify", subscriber_id] => {
+            return test_notify(&Mutex::new(notifier), Some(subscriber_id)).await
+        }
+        ["stats"] => {
+            print!("{}", notifier.stats());
+            return Ok(());
+        }
+        _ => {
+            eprintln!("Usage: evga-notifier [--dry-run] [test-notify [subscriber id] | stats]");
+            return Ok(());
+        }
+    }
+
+    let server_config = notifier.config.application_config.server.clone();
+    let mail_listener = idle::MailListener::from_config(&notifier.config.application_config);
+    // Shared with the server, which changes subscribers when they text us
+    let notifier = Arc::new(Mutex::new(notifier));
+    if let Some(server_config) = server_config {
+        let notifier = notifier.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server::serve(notifier, server_config).await {
+                eprintln!("Stopped listening for texts: {}", e);
+            }
+        });
+    }
+    if let Some(mail_listener) = mail_listener {
+        mail_listener.spawn(notifier.clone());
+    }
+
     loop {
-        let runtime = match run_bot(&mut notifier).await {
+        let runtime = match run_bot(&notifier).await {
             Ok(runtime) => runtime,
             Err(e) => {
                 eprintln!("Error occurred: {}", e);
                 0
             }
         };
+        let mut notifier = notifier.lock().await;
 
         let wait_time = if let Some(timeout) = notifier.config.application_config.daemon_timeout {
             timeout
@@ -126,6 +768,8 @@ async fn main() -> Result<(), NotifyError> {
         if !notifier.daemon_mode() {
             break;
         }
+        // Let the server in while we wait
+        drop(notifier);
 
         // Otherwise, delay for the rest of the 30 second cycle
         tokio::time::delay_for(std::time::Duration::from_secs(wait_time)).await;
@@ -134,27 +778,163 @@ async fn main() -> Result<(), NotifyError> {
     Ok(())
 }
 
-async fn run_bot(notifier: &mut Notifier) -> Result<i64, NotifyError> {
+async fn run_bot(notifier: &Mutex<Notifier>) -> Result<i64, NotifyError> {
     let start = Local::now();
     // Check the scraped websites
-    let scraped_set = scraping::get_providers_from_scraping(notifier).await?;
-    // Check the mail providers
-    let email_set = mail::get_providers_from_mail(notifier).await?;
-
-    // Only send a message if we haven't sent one in the last 5 minutes
-    for product in email_set.iter().chain(scraped_set.iter()) {
-        // If we found any providers, send the messages
-        // If it results in an error print the error
-        if let Err(e) = notifier.handle_found_product(product).await {
-            eprintln!("Provider {:?} had issue: {}", product, e);
-        } else {
-            // If we don't have an error, update the last notification sent timer
-            notifier.config.application_config.last_notification_sent = Local::now();
+    let scraped = scraping::get_providers_from_scraping(notifier).await?;
+    let mqtt = notifier.lock().await.mqtt.clone();
+    if let Some(mqtt) = mqtt {
+        if let Err(e) = mqtt
+            .publish_states(&scraped.in_stock, &scraped.out_of_stock)
+            .await
+        {
+            eprintln!("Publishing MQTT states failed: {}", e);
         }
     }
+    // Check the mail providers, unless the listener is already watching for mail
+    let mail = mail::get_providers_from_mail(notifier).await?;
+
+    // Send everything we found in one go
+    let found = mail
+        .iter()
+        .flat_map(|matches| matches.products.iter().cloned())
+        .chain(scraped.in_stock)
+        .collect::<Vec<Product>>();
+    handle_found_products(notifier, &found).await;
+    // Only now is the mail that was found done with
+    if let Some(mail) = mail {
+        notifier.lock().await.mark_mail_seen(mail);
+    }
+
+    // Try Discord messages that didn't go through earlier again
+    notifier::discord::send_queued(notifier).await;
+
+    // See whether the texts we've sent made it
+    notifier::twilio::poll_text_statuses(notifier).await;
+
+    // Follow up on high priority alerts nobody has acknowledged
+    notifier::voice::run_escalations(notifier).await;
+
+    // Let the admin know if texts and calls are about to stop
+    notifier::budget::check_budget(notifier).await;
+
+    // Catch up anyone whose quiet hours just ended
+    send_deferred_digests(notifier).await;
+
+    let mut notifier = notifier.lock().await;
+    notifier.report_delivery_failures();
 
     // Once we've run through re-write our config
-    write_config(notifier).await?;
+    write_config(&mut notifier).await?;
     let end = Local::now();
     Ok((end - start).num_seconds())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{self, HttpStub, Reply};
+
+    #[tokio::test]
+    async fn only_texts_wait_out_the_cooldown() {
+        let telegram = HttpStub::start(|_| Reply::json(200, serde_json::json!({ "ok": true })));
+        let twilio = HttpStub::start(|_| {
+            Reply::json(201, serde_json::json!({ "sid": "SM1", "status": "queued" }))
+        });
+        let mut notifier = stub::notifier(
+            serde_json::json!({
+                "from_phone_number": "+15553334444",
+                "telegram_bot_token": "token",
+                "telegram_api_url": telegram.url,
+                "last_notification_sent": Local::now().to_rfc3339(),
+            }),
+            serde_json::json!([{
+                "service": ["bestbuy"],
+                "active": true,
+                "telegram_chat_id": 42,
+                "to_phone_number": "+15551112222",
+            }]),
+        );
+        notifier.twilio = Some(notifier::twilio::Client::new("AC1", "token", &twilio.url));
+        let notifier = Mutex::new(notifier);
+
+        handle_found_products(&notifier, &[stub::product("RTX 3080")]).await;
+        assert_eq!(telegram.requests().len(), 1);
+        assert!(twilio.requests().is_empty());
+
+        notifier
+            .lock()
+            .await
+            .config
+            .application_config
+            .last_notification_sent = Local::now() - Duration::hours(1);
+        handle_found_products(&notifier, &[stub::product("RTX 3090")]).await;
+        assert_eq!(telegram.requests().len(), 2);
+        assert_eq!(twilio.requests().len(), 1);
+        assert!(!notifier
+            .lock()
+            .await
+            .config
+            .application_config
+            .should_send_notification());
+    }
+
+    #[tokio::test]
+    async fn escalations_need_a_text_to_acknowledge() {
+        let notifier = Mutex::new(stub::notifier(
+            serde_json::json!({
+                "from_phone_number": "+15553334444",
+                "high_priority_tags": ["fe"],
+                "escalation": { "delay_minutes": 5 },
+            }),
+            serde_json::json!([{
+                "service": ["bestbuy"], "active": true, "to_phone_number": "+15551112222",
+            }]),
+        ));
+        let product = match stub::product("RTX 3080") {
+            Product::BestBuy(details) => Product::BestBuy(ProductDetails {
+                tags: Some(vec!["fe".to_string()]),
+                ..details
+            }),
+            _ => unreachable!(),
+        };
+        let products = [product];
+
+        let refused = HttpStub::start(|_| {
+            Reply::json(
+                400,
+                serde_json::json!({ "code": 21211, "message": "Invalid" }),
+            )
+        });
+        notifier.lock().await.twilio =
+            Some(notifier::twilio::Client::new("AC1", "token", &refused.url));
+        handle_found_products(&notifier, &products).await;
+        assert!(notifier
+            .lock()
+            .await
+            .config
+            .application_config
+            .pending_escalations
+            .is_none());
+
+        let twilio = HttpStub::start(|_| {
+            Reply::json(201, serde_json::json!({ "sid": "SM1", "status": "queued" }))
+        });
+        let mut locked = notifier.lock().await;
+        locked.twilio = Some(notifier::twilio::Client::new("AC1", "token", &twilio.url));
+        // The refused text turned them off
+        locked.config.subscribers[0].sms_disabled = None;
+        locked.config.application_config.last_notification_sent = Local::now() - Duration::hours(1);
+        drop(locked);
+        handle_found_products(&notifier, &products).await;
+        let pending = notifier
+            .lock()
+            .await
+            .config
+            .application_config
+            .pending_escalations
+            .clone()
+            .unwrap();
+        assert_eq!(pending[0].phone, "+15551112222");
+    }
+}
@@ -0,0 +1,470 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::PoisonError;
+
+use chrono::{Duration, Local};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    notifier::twilio::{TextChannel, TextRecord},
+    Notifier,
+};
+
+// Days of spend kept for stats, enough to cover the last few months
+const SPEND_HISTORY_DAYS: i64 = 93;
+const DEFAULT_WARN_AT: f64 = 0.8;
+
+// What Twilio charges, in dollars, and how much we're allowed to spend
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Budget {
+    pub sms_segment_cost: f64,
+    pub mms_cost: f64,
+    pub whatsapp_cost: f64,
+    // Escalation calls are short, so each is counted as one billed minute
+    pub call_cost: f64,
+    pub daily_limit: Option<f64>,
+    pub monthly_limit: Option<f64>,
+    // How far into a limit the admin is warned, defaults to 0.8
+    pub warn_at: Option<f64>,
+}
+
+// Where warnings meant for whoever runs the notifier go
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdminContact {
+    pub telegram_chat_id: Option<i64>,
+    pub email: Option<String>,
+    // Name of the push target in `push_targets`
+    pub push_target: Option<String>,
+}
+
+// What was sent to one subscriber in one day
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Usage {
+    pub sms_segments: u32,
+    pub mms: u32,
+    pub whatsapp: u32,
+    pub calls: u32,
+    // Dollars, at the prices configured when it was sent
+    pub cost: f64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.sms_segments += other.sms_segments;
+        self.mms += other.mms;
+        self.whatsapp += other.whatsapp;
+        self.calls += other.calls;
+        self.cost += other.cost;
+    }
+}
+
+// Spend per day, keyed by date and then subscriber id
+pub type SpendLog = BTreeMap<String, HashMap<String, Usage>>;
+
+// Spend shared by the notifier and every sender copied from it
+#[derive(Debug, Default)]
+pub struct SpendLedger {
+    // Dollars set aside for texts and calls being sent, that aren't in the spend log yet
+    reserved: f64,
+    // What the spend log had for today and this month when something was last logged, keyed by the period
+    daily: Option<(String, f64)>,
+    monthly: Option<(String, f64)>,
+}
+
+fn today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+fn this_month() -> String {
+    Local::now().format("%Y-%m").to_string()
+}
+
+// Roughly how many segments Twilio bills a text as. Anything outside ASCII goes as UCS-2, which fits less in each
+fn segments(text: &str) -> u32 {
+    let (single, multipart) = if text.is_ascii() {
+        (160, 153)
+    } else {
+        (70, 67)
+    };
+    let length = text.chars().count();
+    if length <= single {
+        1
+    } else {
+        length.div_ceil(multipart) as u32
+    }
+}
+
+impl Notifier {
+    // Everything spent on days whose date starts with the prefix, a day or a month
+    pub fn spend_since(&self, prefix: &str) -> Usage {
+        let mut total = Usage::default();
+        for usage in self
+            .config
+            .application_config
+            .spend_log
+            .iter()
+            .flatten()
+            .filter(|(day, _)| day.starts_with(prefix))
+            .flat_map(|(_, subscribers)| subscribers.values())
+        {
+            total.add(usage);
+        }
+        total
+    }
+
+    // Set aside what a text or call is expected to cost, if it fits in what's left today and this month
+    // Every sender shares the ledger, so sends going out at the same time from the main loop,
+    // the mail listener and escalations each set theirs aside first and together can't go over
+    pub fn reserve_spend(&self, cost: f64) -> bool {
+        let budget = match &self.config.application_config.budget {
+            Some(budget) => budget,
+            None => return true,
+        };
+
+        let mut ledger = self
+            .spend_ledger
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // The ledger has the latest spend once anything's been logged, a sender's copy of the log may not
+        let fits = |limit: Option<f64>, logged: &Option<(String, f64)>, period: &str| {
+            let limit = match limit {
+                Some(limit) => limit,
+                None => return true,
+            };
+            let spent = match logged {
+                Some((logged_period, spent)) if logged_period == period => *spent,
+                _ => self.spend_since(period).cost,
+            };
+            let committed = spent + ledger.reserved;
+            committed < limit && committed + cost <= limit
+        };
+        if !(fits(budget.daily_limit, &ledger.daily, &today())
+            && fits(budget.monthly_limit, &ledger.monthly, &this_month()))
+        {
+            return false;
+        }
+        ledger.reserved += cost;
+        true
+    }
+
+    // Hand back what was set aside, once what was actually spent has been logged
+    pub fn release_spend(&self, cost: f64) {
+        let mut ledger = self
+            .spend_ledger
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        ledger.reserved = (ledger.reserved - cost).max(0.0);
+    }
+
+    // What sending these texts should cost, before Twilio tells us how many segments they were
+    pub fn estimate_text_cost(&self, channel: TextChannel, parts: &[String]) -> f64 {
+        let budget = match &self.config.application_config.budget {
+            Some(budget) => budget,
+            None => return 0.0,
+        };
+
+        parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| match channel.billed_as(i) {
+                TextChannel::Sms => segments(part) as f64 * budget.sms_segment_cost,
+                TextChannel::Mms => budget.mms_cost,
+                TextChannel::Whatsapp => budget.whatsapp_cost,
+            })
+            .sum()
+    }
+
+    pub fn call_cost(&self) -> f64 {
+        self.config
+            .application_config
+            .budget
+            .as_ref()
+            .map(|budget| budget.call_cost)
+            .unwrap_or_default()
+    }
+
+    // Count the texts that actually went out
+    pub fn record_text_spend(&mut self, subscriber_id: &str, texts: &[TextRecord]) {
+        let budget = self.config.application_config.budget.clone();
+        let mut usage = Usage::default();
+        for text in texts.iter().filter(|text| text.sid.is_some()) {
+            // Recorded as what each part was billed as, see `TextChannel::billed_as`
+            match text.channel.unwrap_or_default() {
+                TextChannel::Sms => usage.sms_segments += text.segments.unwrap_or(1),
+                TextChannel::Mms => usage.mms += 1,
+                TextChannel::Whatsapp => usage.whatsapp += 1,
+            }
+        }
+        if let Some(budget) = budget {
+            usage.cost = usage.sms_segments as f64 * budget.sms_segment_cost
+                + usage.mms as f64 * budget.mms_cost
+                + usage.whatsapp as f64 * budget.whatsapp_cost;
+        }
+        self.record_spend(subscriber_id, usage);
+    }
+
+    pub fn record_call_spend(&mut self, subscriber_id: &str) {
+        let cost = self.call_cost();
+        self.record_spend(
+            subscriber_id,
+            Usage {
+                calls: 1,
+                cost,
+                ..Usage::default()
+            },
+        );
+    }
+
+    fn record_spend(&mut self, subscriber_id: &str, usage: Usage) {
+        let log = self
+            .config
+            .application_config
+            .spend_log
+            .get_or_insert_with(BTreeMap::new);
+        log.entry(today())
+            .or_default()
+            .entry(subscriber_id.to_string())
+            .or_default()
+            .add(&usage);
+
+        let oldest = (Local::now() - Duration::days(SPEND_HISTORY_DAYS))
+            .format("%Y-%m-%d")
+            .to_string();
+        log.retain(|day, _| *day >= oldest);
+
+        let (day, month) = (today(), this_month());
+        let daily = Some((day.clone(), self.spend_since(&day).cost));
+        let monthly = Some((month.clone(), self.spend_since(&month).cost));
+        let mut ledger = self
+            .spend_ledger
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        ledger.daily = daily;
+        ledger.monthly = monthly;
+    }
+
+    // Warnings for spend getting close to or reaching a limit, each only once per day or month
+    fn due_budget_warnings(&mut self) -> Vec<String> {
+        let budget = match &self.config.application_config.budget {
+            Some(budget) => budget.clone(),
+            None => return vec![],
+        };
+        let warn_at = budget.warn_at.unwrap_or(DEFAULT_WARN_AT);
+
+        let mut warnings = vec![];
+        for (period, key, limit) in [
+            ("daily", today(), budget.daily_limit),
+            ("monthly", this_month(), budget.monthly_limit),
+        ] {
+            let limit = match limit {
+                Some(limit) if limit > 0.0 => limit,
+                _ => continue,
+            };
+            let spent = self.spend_since(&key).cost;
+            let level = if spent >= limit {
+                "reached"
+            } else if spent >= limit * warn_at {
+                "nearly reached"
+            } else {
+                continue;
+            };
+
+            let warning_key = format!("{} {} {}", period, key, level);
+            let warned = self
+                .config
+                .application_config
+                .budget_warnings
+                .get_or_insert_with(Vec::new);
+            if warned.contains(&warning_key) {
+                continue;
+            }
+            warned.push(warning_key);
+            warnings.push(format!(
+                "RTX Notifier has {} its {} Twilio budget: ${:.2} of ${:.2} spent.{}",
+                level,
+                period,
+                spent,
+                limit,
+                if level == "reached" {
+                    " Texts and calls are paused until it resets."
+                } else {
+                    ""
+                }
+            ));
+        }
+
+        // Warnings from past days and months will never come up again
+        let (day, month) = (today(), this_month());
+        if let Some(warned) = &mut self.config.application_config.budget_warnings {
+            warned.retain(|key| {
+                let period = key.split(' ').nth(1);
+                period == Some(day.as_str()) || period == Some(month.as_str())
+            });
+        }
+
+        warnings
+    }
+
+    // Send a message to the admin through everything they can be reached on, logging it either way
+    pub async fn notify_admin(&self, message: &str) {
+        eprintln!("{}", message);
+        let application_config = &self.config.application_config;
+        let admin = match &application_config.admin {
+            Some(admin) => admin,
+            None => return,
+        };
+
+        if let (Some(token), Some(chat_id)) = (
+            &application_config.telegram_bot_token,
+            admin.telegram_chat_id,
+        ) {
+            if let Err(e) = crate::notifier::telegram::send_message(
+                application_config.telegram_api_url(),
+                token,
+                chat_id,
+                message,
+                &[],
+            )
+            .await
+            {
+                eprintln!("Telegram message to the admin failed: {}", e);
+            }
+        }
+
+        if let (Some(transport), Some(from), Some(to)) = (
+            &self.smtp,
+            &application_config.smtp_from_address,
+            &admin.email,
+        ) {
+            if let Err(e) =
+                crate::notifier::email::send_email(transport, from, to, message, "", &[], None)
+                    .await
+            {
+                eprintln!("Email to the admin failed: {}", e);
+            }
+        }
+
+        if let Some(target) = admin.push_target.as_ref().and_then(|name| {
+            application_config
+                .push_targets
+                .as_ref()
+                .and_then(|targets| targets.get(name))
+        }) {
+            if let Err(e) = crate::notifier::push::send_push(
+                target,
+                message,
+                &[],
+                crate::notifier::push::Priority::High,
+            )
+            .await
+            {
+                eprintln!("Push to the admin failed: {}", e);
+            }
+        }
+    }
+}
+
+// Let the admin know when spend gets close to or reaches a limit, without holding the lock while it's sent
+pub async fn check_budget(notifier: &Mutex<Notifier>) {
+    let (warnings, sender) = {
+        let mut notifier = notifier.lock().await;
+        (notifier.due_budget_warnings(), notifier.sender())
+    };
+
+    for warning in warnings {
+        sender.notify_admin(&warning).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{self, HttpStub, Reply};
+
+    #[tokio::test]
+    async fn texts_sent_together_stay_within_the_budget() {
+        let twilio = HttpStub::start(|_| {
+            Reply::json(
+                201,
+                serde_json::json!({ "sid": "SM1", "status": "queued", "num_segments": "1" }),
+            )
+        });
+        let mut notifier = stub::notifier(
+            serde_json::json!({
+                "from_phone_number": "+15553334444",
+                "budget": {
+                    "sms_segment_cost": 1.0,
+                    "mms_cost": 1.0,
+                    "whatsapp_cost": 1.0,
+                    "call_cost": 1.0,
+                    "daily_limit": 1.5,
+                },
+            }),
+            serde_json::json!([
+                { "service": ["bestbuy"], "active": true, "to_phone_number": "+15551112222" },
+                { "service": ["bestbuy"], "active": true, "to_phone_number": "+15556667777" },
+            ]),
+        );
+        notifier.twilio = Some(crate::notifier::twilio::Client::new(
+            "AC123",
+            "token",
+            &twilio.url,
+        ));
+        let notifier = tokio::sync::Mutex::new(notifier);
+
+        crate::handle_found_products(&notifier, &[stub::product("RTX 3080")]).await;
+
+        assert_eq!(twilio.requests().len(), 1);
+        let notifier = notifier.lock().await;
+        assert_eq!(notifier.spend_since(&today()).cost, 1.0);
+        assert!(!notifier.reserve_spend(1.0));
+    }
+
+    #[test]
+    fn senders_share_what_is_set_aside() {
+        let mut notifier = stub::notifier(
+            serde_json::json!({
+                "budget": {
+                    "sms_segment_cost": 1.0,
+                    "mms_cost": 1.0,
+                    "whatsapp_cost": 1.0,
+                    "call_cost": 1.0,
+                    "daily_limit": 1.5,
+                },
+            }),
+            serde_json::json!([]),
+        );
+        let (first, second) = (notifier.sender(), notifier.sender());
+
+        assert!(first.reserve_spend(1.0));
+        assert!(!second.reserve_spend(1.0));
+
+        // Handed back once it's logged, and senders copied before then still count it
+        notifier.record_call_spend("+15551112222");
+        notifier.release_spend(notifier.call_cost());
+        assert!(!second.reserve_spend(1.0));
+        assert!(second.reserve_spend(0.5));
+    }
+
+    #[test]
+    fn only_the_first_part_of_an_mms_costs_an_mms() {
+        let notifier = stub::notifier(
+            serde_json::json!({
+                "budget": {
+                    "sms_segment_cost": 0.01,
+                    "mms_cost": 0.02,
+                    "whatsapp_cost": 0.005,
+                    "call_cost": 0.1,
+                },
+            }),
+            serde_json::json!([]),
+        );
+        let parts = vec!["a".repeat(300), "b".repeat(200)];
+
+        let cost = notifier.estimate_text_cost(TextChannel::Mms, &parts);
+
+        // The second part goes out as two SMS segments
+        assert!((cost - 0.04).abs() < 1e-9);
+        assert!((notifier.estimate_text_cost(TextChannel::Whatsapp, &parts) - 0.01).abs() < 1e-9);
+    }
+}
@@ -1,44 +1,423 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use reqwest::{
+    header::HeaderMap,
+    multipart::{Form, Part},
+};
 use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    notifier::{dry_run, http_client, product_names, template::Channel, Alert, DeliveryFailure},
+    product::{tag_expression_matches, Product, ShipsFrom, TagFilter},
+    Notifier, NotifyError,
+};
+
+// Discord rejects webhooks with more embeds than this
+const MAX_EMBEDS: usize = 10;
+// How many times we'll try a message before giving up on it, one try a cycle
+const MAX_ATTEMPTS: u32 = 5;
+// Doubled after every failed attempt, though it's never tried again sooner than the next cycle
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+// A Discord webhook that only gets the products matching its filters. Filters left out match everything
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiscordTarget {
+    pub url: String,
+    // Provider keys, like "evga" or "newegg"
+    pub providers: Option<Vec<String>>,
+    pub tags: Option<TagFilter>,
+    // Price range in dollars. Products without a known price always match
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    // Who ships the offer, like ["third_party"] for marketplace listings
+    pub ships_from: Option<Vec<ShipsFrom>>,
+    // Override the application's `discord_avatar_url` and `discord_mentions` for this webhook
+    pub avatar_url: Option<String>,
+    pub mentions: Option<HashMap<String, Vec<String>>>,
+}
+
+impl DiscordTarget {
+    // A target for the plain `discord_url`, which gets everything
+    pub fn unfiltered(url: &str) -> Self {
+        DiscordTarget {
+            url: url.to_string(),
+            providers: None,
+            tags: None,
+            min_price: None,
+            max_price: None,
+            ships_from: None,
+            avatar_url: None,
+            mentions: None,
+        }
+    }
+
+    pub fn accepts(&self, product: &Product) -> bool {
+        if let Some(providers) = &self.providers {
+            if !providers
+                .iter()
+                .any(|provider| provider.eq_ignore_ascii_case(product.to_key()))
+            {
+                return false;
+            }
+        }
+
+        if let Some(tags) = &self.tags {
+            if !tags.matches(product.get_tags()) {
+                return false;
+            }
+        }
 
-use crate::{product::Product, NotifyError};
-
-pub async fn send_webhook(product: &Product, url: &str) -> Result<(), NotifyError> {
-    let message = product.new_stock_message();
-
-    let webhook_body = DiscordWebhook {
-        username: Some("RTX Notifier".to_string()),
-        avatar_url: Some(
-            "https://images.evga.com/products/gallery/png/10G-P5-3897-KR_LG_1.png".to_string(),
-        ),
-        content: None,
-        embeds: vec![WebhookEmbed {
-            title: Some(format!("Found Inventory {}", product.to_key())),
-            url: Some(product.get_url()?.to_string()),
-            description: Some(message.clone()),
-            color: 0,
-            fields: vec![],
-        }],
+        let offer = product.get_offer();
+        if let Some(price) = offer.and_then(|offer| offer.price()) {
+            let too_cheap = matches!(self.min_price, Some(min) if price < min);
+            let too_expensive = matches!(self.max_price, Some(max) if price > max);
+            if too_cheap || too_expensive {
+                return false;
+            }
+        }
+
+        match (&self.ships_from, offer.and_then(|offer| offer.ships_from)) {
+            (Some(allowed), Some(ships_from)) => allowed.contains(&ships_from),
+            _ => true,
+        }
+    }
+}
+
+// A message Discord hasn't taken yet. They're kept in the config, so anything that fails is tried again next cycle
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingWebhook {
+    pub url: String,
+    pub payload: String,
+    // Page snapshots uploaded with it, as file names and contents
+    pub files: Vec<(String, String)>,
+    // What's in it, to report if we give up on it
+    pub products: Vec<String>,
+    pub attempts: u32,
+    // Not tried again before this, so rate limits are respected and errors backed off from
+    pub retry_at: DateTime<Local>,
+}
+
+// How one try at sending a webhook went
+enum Attempt {
+    // With how long until the rate limit bucket refills, when it's empty
+    Sent(Option<Duration>),
+    // Worth trying again after waiting this long
+    Retry(NotifyError, Duration),
+    Failed(NotifyError),
+}
+
+// The messages that carry these alerts to the webhook, ready to be queued
+pub fn build_webhooks(
+    alerts: &[&Alert],
+    url: &str,
+    avatar_url: Option<&str>,
+    // Mentions to add to the message, keyed by the product tag expression they're for
+    mentions: Option<&HashMap<String, Vec<String>>>,
+) -> Vec<PendingWebhook> {
+    let mut webhooks = vec![];
+    for chunk in alerts.chunks(MAX_EMBEDS) {
+        let webhook_body = DiscordWebhook {
+            username: Some("RTX Notifier".to_string()),
+            avatar_url: avatar_url.map(str::to_string),
+            // Embeds can't ping anyone, so mentions go in the message itself
+            content: chunk_mentions(chunk, mentions),
+            embeds: chunk.iter().map(|alert| build_embed(alert)).collect(),
+        };
+
+        let payload = serde_json::to_string(&webhook_body).unwrap();
+        // Attach the page snapshots so we can tell a real restock from a layout change
+        let files = chunk
+            .iter()
+            .filter_map(|alert| alert.snapshot_file())
+            .collect::<Vec<(String, String)>>();
+
+        let destination = format!(
+            "Discord webhook {} with {} snapshots",
+            redact_url(url),
+            files.len()
+        );
+        if dry_run(&destination, &payload) {
+            continue;
+        }
+
+        webhooks.push(PendingWebhook {
+            url: url.to_string(),
+            payload,
+            files,
+            products: product_names(chunk),
+            attempts: 0,
+            retry_at: Local::now(),
+        });
+    }
+
+    webhooks
+}
+
+// Post a webhook once, with its files as a multipart form
+async fn attempt(client: &reqwest::Client, webhook: &PendingWebhook) -> Attempt {
+    let request = client.post(&webhook.url);
+    let request = if webhook.files.is_empty() {
+        request
+            .body(webhook.payload.clone())
+            .header("Content-Type", "application/json")
+    } else {
+        let mut form = Form::new().text("payload_json", webhook.payload.clone());
+        for (i, (file_name, contents)) in webhook.files.iter().enumerate() {
+            let part = match Part::text(contents.clone())
+                .file_name(file_name.clone())
+                .mime_str("text/html")
+            {
+                Ok(part) => part,
+                Err(e) => return Attempt::Failed(NotifyError::WebRequestFailed(e)),
+            };
+            form = form.part(format!("files[{}]", i), part);
+        }
+        request.multipart(form)
     };
 
-    let payload = serde_json::to_string(&webhook_body).unwrap();
+    let backoff = BASE_BACKOFF * 2u32.pow(webhook.attempts.saturating_sub(1));
+    match request.send().await {
+        // When we've used up the bucket Discord tells us how long until it refills, so we can wait that out instead of getting a 429
+        Ok(res) if res.status().is_success() => Attempt::Sent(bucket_reset(res.headers())),
+        Ok(res) if res.status().as_u16() == 429 => {
+            Attempt::Retry(NotifyError::RateLimit, retry_after(res).await)
+        }
+        Ok(res) if res.status().is_server_error() => {
+            Attempt::Retry(NotifyError::WebServer(res.status()), backoff)
+        }
+        // Anything else wrong with the request won't be fixed by sending it again
+        Ok(res) => Attempt::Failed(NotifyError::WebClient(res.status())),
+        Err(e) if e.is_builder() => Attempt::Failed(NotifyError::WebRequestFailed(e)),
+        Err(e) => Attempt::Retry(NotifyError::WebRequestFailed(e), backoff),
+    }
+}
+
+impl Notifier {
+    // Queue messages for Discord, sent by `send_queued`
+    pub fn queue_discord_webhooks(&mut self, webhooks: Vec<PendingWebhook>) {
+        self.config
+            .application_config
+            .discord_queue
+            .get_or_insert_with(Vec::new)
+            .extend(webhooks);
+    }
+}
 
-    let client = reqwest::Client::new();
-    let res = client
-        .post(url)
-        .body(payload.clone())
-        .header("Content-Type", "application/json")
-        .send()
+// Try each queued Discord message that's due once. Whatever can be tried again waits for a later cycle,
+// so nothing sleeps here, and we give up on a message after MAX_ATTEMPTS tries
+// The queue is taken out of the notifier first, so it isn't locked while we wait on Discord
+pub async fn send_queued(notifier: &Mutex<Notifier>) {
+    let queue = match notifier
+        .lock()
         .await
-        .map_err(NotifyError::WebRequestFailed)?;
+        .config
+        .application_config
+        .discord_queue
+        .take()
+    {
+        Some(queue) if !queue.is_empty() => queue,
+        _ => return,
+    };
+
+    let (kept, failures) = send_webhooks(queue).await;
+
+    let mut notifier = notifier.lock().await;
+    if !kept.is_empty() {
+        notifier.queue_discord_webhooks(kept);
+    }
+    notifier.delivery_failures.extend(failures);
+}
 
-    let status = res.status();
-    if !status.is_success() || status.is_server_error() || status.is_client_error() {
-        return Err(NotifyError::WebClient(status));
+// The messages to try again later, and the ones we gave up on
+async fn send_webhooks(queue: Vec<PendingWebhook>) -> (Vec<PendingWebhook>, Vec<DeliveryFailure>) {
+    let client = http_client();
+    // Webhooks that can't take anything else until then
+    let mut limited = HashMap::<String, DateTime<Local>>::new();
+    let mut kept = vec![];
+    let mut failures = vec![];
+    for mut webhook in queue {
+        if let Some(until) = limited.get(&webhook.url) {
+            webhook.retry_at = webhook.retry_at.max(*until);
+        }
+        if webhook.retry_at > Local::now() {
+            kept.push(webhook);
+            continue;
+        }
+
+        webhook.attempts += 1;
+        match attempt(&client, &webhook).await {
+            Attempt::Sent(reset) => {
+                println!(
+                    "Sent discord webhook with {} products and {} snapshots\nPayload: {}",
+                    webhook.products.len(),
+                    webhook.files.len(),
+                    webhook.payload
+                );
+                if let Some(wait) = reset {
+                    limited.insert(webhook.url, after(wait));
+                }
+            }
+            Attempt::Retry(error, wait) if webhook.attempts < MAX_ATTEMPTS => {
+                eprintln!(
+                    "Discord webhook failed: {}, retrying in {:.1}s",
+                    error,
+                    wait.as_secs_f64()
+                );
+                webhook.retry_at = after(wait);
+                if let NotifyError::RateLimit = error {
+                    limited.insert(webhook.url.clone(), webhook.retry_at);
+                }
+                kept.push(webhook);
+            }
+            Attempt::Retry(error, _) | Attempt::Failed(error) => {
+                eprintln!("Discord webhook failed: {}", error);
+                failures.push(DeliveryFailure {
+                    channel: Channel::Discord,
+                    target: redact_url(&webhook.url),
+                    products: webhook.products,
+                    error,
+                });
+            }
+        }
     }
 
-    println!("Sent discord webhook to {}\nPayload: {}", message, payload);
+    (kept, failures)
+}
+
+fn after(wait: Duration) -> DateTime<Local> {
+    Local::now() + chrono::Duration::from_std(wait).unwrap_or_else(|_| chrono::Duration::zero())
+}
+
+// How long a 429 asks us to wait. The body's retry_after is more precise than the header
+async fn retry_after(res: reqwest::Response) -> Duration {
+    let header = header_seconds(res.headers(), "Retry-After");
+    let body = res
+        .text()
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_str::<RateLimited>(&body).ok())
+        .map(|body| body.retry_after);
 
-    Ok(())
+    Duration::from_secs_f64(body.or(header).unwrap_or(1.0).max(0.0))
+}
+
+fn bucket_reset(headers: &HeaderMap) -> Option<Duration> {
+    if header_seconds(headers, "X-RateLimit-Remaining")? > 0.0 {
+        return None;
+    }
+
+    header_seconds(headers, "X-RateLimit-Reset-After")
+        .map(|seconds| Duration::from_secs_f64(seconds.max(0.0)))
+}
+
+fn header_seconds(headers: &HeaderMap, name: &str) -> Option<f64> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok())
+}
+
+// Webhook URLs end in their token, which shouldn't end up in logs
+fn redact_url(url: &str) -> String {
+    match url.trim_end_matches('/').rsplit_once('/') {
+        Some((base, _)) => format!("{}/<token>", base),
+        None => url.to_string(),
+    }
+}
+
+fn build_embed(alert: &Alert) -> WebhookEmbed {
+    let product = &alert.product;
+    let offer = product.get_offer();
+
+    let mut fields = vec![];
+    if let Some(price) = offer.and_then(|offer| offer.price()) {
+        fields.push(EmbedField {
+            name: "Price".to_string(),
+            value: format!("${:.2}", price),
+            inline: true,
+        });
+    }
+    if let Some(seller) = offer.and_then(|offer| offer.seller.as_ref()) {
+        fields.push(EmbedField {
+            name: "Seller".to_string(),
+            value: seller.to_string(),
+            inline: true,
+        });
+    }
+
+    // How long it took from the scraper seeing it to us sending it
+    let latency = (Local::now() - alert.detected_at).num_milliseconds() as f64 / 1000.0;
+
+    WebhookEmbed {
+        title: Some(match product.get_name() {
+            Ok(name) => name.to_string(),
+            Err(_) => format!("Found Inventory {}", product.to_key()),
+        }),
+        url: product.get_url().ok().map(str::to_string),
+        description: Some(alert.message.clone()),
+        color: retailer_color(product),
+        author: Some(Author {
+            name: Some(product.retailer_name().to_string()),
+            url: None,
+            icon_url: None,
+        }),
+        fields,
+        thumbnail: offer
+            .and_then(|offer| offer.image_url.clone())
+            .map(|url| Thumbnail { url }),
+        footer: Some(Footer {
+            text: Some(format!("Sent {:.1}s after detection", latency)),
+            icon_url: None,
+        }),
+        timestamp: Some(alert.detected_at.to_rfc3339()),
+    }
+}
+
+fn retailer_color(product: &Product) -> u32 {
+    match product {
+        Product::Evga(_) => 0x1B75BC,
+        Product::NewEgg(_) => 0xF58220,
+        Product::Nvidia(_) => 0x76B900,
+        Product::BestBuy(_) => 0x0046BE,
+        Product::BnH(_) => 0xC8102E,
+        Product::Amazon(_) => 0xFF9900,
+    }
+}
+
+// Everyone who asked to be pinged about something in this chunk, each mentioned once
+fn chunk_mentions(
+    alerts: &[&Alert],
+    mentions: Option<&HashMap<String, Vec<String>>>,
+) -> Option<String> {
+    let mut content: Vec<&str> = vec![];
+    for (expr, tag_mentions) in mentions.into_iter().flatten() {
+        if alerts
+            .iter()
+            .any(|alert| tag_expression_matches(expr, alert.product.get_tags()))
+        {
+            for mention in tag_mentions {
+                if !content.contains(&mention.as_str()) {
+                    content.push(mention);
+                }
+            }
+        }
+    }
+
+    if content.is_empty() {
+        None
+    } else {
+        Some(content.join(" "))
+    }
+}
+
+// The body of a 429
+#[derive(Deserialize, Debug)]
+struct RateLimited {
+    // Seconds to wait
+    retry_after: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -61,27 +440,25 @@ struct WebhookEmbed {
     title: Option<String>,
     url: Option<String>,
     description: Option<String>,
-    color: usize,
+    color: u32,
+    author: Option<Author>,
     fields: Vec<EmbedField>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct EmbedField {
-    name: Option<String>,
-    value: Option<String>,
-    inline: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     thumbnail: Option<Thumbnail>,
-    image: Option<Image>,
     footer: Option<Footer>,
+    // ISO 8601, Discord shows it in each reader's own time zone
+    timestamp: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct Thumbnail {
-    url: String,
+struct EmbedField {
+    name: String,
+    value: String,
+    inline: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct Image {
+struct Thumbnail {
     url: String,
 }
 
@@ -90,3 +467,82 @@ struct Footer {
     text: Option<String>,
     icon_url: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::stub::{self, HttpStub, Reply};
+
+    #[tokio::test]
+    async fn failures_are_tried_again_next_cycle() {
+        let calls = AtomicUsize::new(0);
+        let discord = HttpStub::start(move |_| match calls.fetch_add(1, Ordering::SeqCst) {
+            0 => Reply::json(500, serde_json::json!({})),
+            _ => Reply::ok(),
+        });
+        let notifier = Mutex::new(stub::notifier(serde_json::json!({}), serde_json::json!([])));
+        let alert = stub::alert(stub::product("RTX 3080"));
+        let url = format!("{}/api/webhooks/1/token", discord.url);
+
+        notifier
+            .lock()
+            .await
+            .queue_discord_webhooks(build_webhooks(&[&alert], &url, None, None));
+        send_queued(&notifier).await;
+
+        let queue = notifier
+            .lock()
+            .await
+            .config
+            .application_config
+            .discord_queue
+            .clone()
+            .unwrap();
+        assert_eq!(discord.requests().len(), 1);
+        assert_eq!(queue[0].attempts, 1);
+        assert!(queue[0].retry_at > Local::now());
+        assert!(notifier.lock().await.delivery_failures.is_empty());
+
+        // Nothing is sent before it's due
+        send_queued(&notifier).await;
+        assert_eq!(discord.requests().len(), 1);
+
+        let mut locked = notifier.lock().await;
+        locked
+            .config
+            .application_config
+            .discord_queue
+            .as_mut()
+            .unwrap()[0]
+            .retry_at = Local::now();
+        drop(locked);
+        send_queued(&notifier).await;
+        assert_eq!(discord.requests().len(), 2);
+        let notifier = notifier.lock().await;
+        assert!(notifier.config.application_config.discord_queue.is_none());
+        assert!(notifier.delivery_failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bad_requests_are_given_up_on() {
+        let discord = HttpStub::start(|_| Reply::json(400, serde_json::json!({})));
+        let notifier = Mutex::new(stub::notifier(serde_json::json!({}), serde_json::json!([])));
+        let alert = stub::alert(stub::product("RTX 3080"));
+        let url = format!("{}/api/webhooks/1/token", discord.url);
+
+        notifier
+            .lock()
+            .await
+            .queue_discord_webhooks(build_webhooks(&[&alert], &url, None, None));
+        send_queued(&notifier).await;
+
+        let notifier = notifier.lock().await;
+        assert!(notifier.config.application_config.discord_queue.is_none());
+        assert_eq!(notifier.delivery_failures.len(), 1);
+        assert!(notifier.delivery_failures[0]
+            .target
+            .ends_with("/api/webhooks/1/<token>"));
+    }
+}
@@ -0,0 +1,225 @@
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Tls, TlsParameters},
+    },
+    Message, SmtpTransport, Transport,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifier::{dry_run, Alert},
+    NotifyError,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Connect in plain text and upgrade with STARTTLS, usually port 587
+    StartTls,
+    // Connect over TLS from the start, usually port 465
+    Tls,
+    // No encryption at all. Only meant for a local SMTP sink
+    None,
+}
+
+pub fn get_smtp(
+    host: &str,
+    port: u16,
+    security: SmtpSecurity,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<SmtpTransport, NotifyError> {
+    let tls = match security {
+        SmtpSecurity::None => Tls::None,
+        _ => {
+            let parameters = TlsParameters::new(host.to_string())
+                .map_err(|e| NotifyError::SmtpConnection(Box::new(e)))?;
+            if security == SmtpSecurity::Tls {
+                Tls::Wrapper(parameters)
+            } else {
+                Tls::Required(parameters)
+            }
+        }
+    };
+
+    let mut builder = SmtpTransport::builder_dangerous(host).port(port).tls(tls);
+    if let (Some(username), Some(password)) = (username, password) {
+        builder = builder.credentials(Credentials::new(username.to_string(), password.to_string()));
+    }
+
+    Ok(builder.build())
+}
+
+pub async fn send_email(
+    transport: &SmtpTransport,
+    from: &str,
+    to: &str,
+    message: &str,
+    // Added after the message, templated or not, like claim links
+    extras: &str,
+    alerts: &[&Alert],
+    // Alerts rendered from the email template, used for the HTML body instead of the built in one
+    templated: Option<&[Alert]>,
+) -> Result<(), NotifyError> {
+    let subject = match alerts {
+        [] => "RTX Notifier".to_string(),
+        [alert] => match alert.product.get_name() {
+            Ok(name) => format!("{} has {} in stock", alert.product.retailer_name(), name),
+            Err(_) => format!("{} has new products", alert.product.retailer_name()),
+        },
+        _ => format!("Found {} products in stock", alerts.len()),
+    };
+
+    let email = Message::builder()
+        .from(
+            from.parse::<Mailbox>()
+                .map_err(|_| NotifyError::EmailBuild)?,
+        )
+        .to(to.parse::<Mailbox>().map_err(|_| NotifyError::EmailBuild)?)
+        .subject(subject)
+        .multipart(MultiPart::alternative_plain_html(
+            format!("{}{}", message, extras),
+            format!(
+                "<html><body>{}{}</body></html>",
+                match templated {
+                    Some(templated) => templated_html_body(templated),
+                    None => html_body(message, alerts),
+                },
+                match extras.trim() {
+                    "" => String::new(),
+                    extras => paragraph(extras),
+                }
+            ),
+        ))
+        .map_err(|_| NotifyError::EmailBuild)?;
+    if dry_run(
+        &format!("Email to {}", to),
+        &String::from_utf8_lossy(&email.formatted()),
+    ) {
+        return Ok(());
+    }
+
+    // lettre's SMTP client blocks, so it gets a thread of its own rather than holding up everything else
+    let transport = transport.clone();
+    tokio::task::spawn_blocking(move || transport.send(&email))
+        .await
+        .map_err(NotifyError::SmtpTask)?
+        .map_err(|e| NotifyError::SmtpSend(Box::new(e)))?;
+
+    println!("Sent [{}] email to {}", message, to);
+
+    Ok(())
+}
+
+fn html_body(message: &str, alerts: &[&Alert]) -> String {
+    let mut body = String::new();
+    if alerts.is_empty() {
+        body.push_str(&paragraph(message));
+    }
+
+    for alert in alerts {
+        let product = &alert.product;
+        let text = escape(&product.new_stock_message());
+        let price = product
+            .get_offer()
+            .and_then(|offer| offer.price())
+            .map(|price| format!(" - ${:.2}", price))
+            .unwrap_or_default();
+        match product.get_url() {
+            Ok(url) => body.push_str(&format!(
+                "<p><strong>{}</strong>: <a href=\"{}\">{}</a>{}</p>",
+                product.retailer_name(),
+                escape(url),
+                text,
+                price
+            )),
+            Err(_) => body.push_str(&format!(
+                "<p><strong>{}</strong>: {}</p>",
+                product.retailer_name(),
+                text
+            )),
+        }
+        // Whatever else the message has to say, like other listings of the card
+        if let Some(rest) = alert
+            .message
+            .strip_prefix(&product.new_stock_message())
+            .map(str::trim)
+            .filter(|rest| !rest.is_empty())
+        {
+            body.push_str(&paragraph(rest));
+        }
+    }
+
+    body
+}
+
+fn templated_html_body(alerts: &[Alert]) -> String {
+    alerts
+        .iter()
+        .map(|alert| alert.message.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+// Plain text as an HTML paragraph, keeping its line breaks
+pub fn paragraph(text: &str) -> String {
+    format!("<p>{}</p>", escape(text).replace('\n', "<br>"))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{self, SmtpSink};
+    use mailparse::MailHeaderMap;
+
+    #[tokio::test]
+    async fn sends_plain_and_html_parts() {
+        let sink = SmtpSink::start().await;
+        let transport = get_smtp("127.0.0.1", sink.port, SmtpSecurity::None, None, None).unwrap();
+        let alert = stub::alert(stub::product("RTX 3080"));
+
+        send_email(
+            &transport,
+            "notifier@example.com",
+            "buyer@example.com",
+            &alert.message,
+            "\n\nBought it? https://example.com/claim/abc",
+            &[&alert],
+            None,
+        )
+        .await
+        .unwrap();
+
+        let messages = sink.messages();
+        assert_eq!(messages.len(), 1);
+        let mail = mailparse::parse_mail(messages[0].as_bytes()).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("Subject").unwrap(),
+            "Bestbuy has RTX 3080 in stock"
+        );
+        assert_eq!(
+            // SMTP sends line breaks as CRLF
+            mail.subparts[0]
+                .get_body()
+                .unwrap()
+                .trim()
+                .replace("\r\n", "\n"),
+            format!(
+                "{}\n\nBought it? https://example.com/claim/abc",
+                alert.message
+            )
+        );
+        let html = mail.subparts[1].get_body().unwrap();
+        assert!(html.contains("<a href=\"https://www.bestbuy.com/site/RTX-3080\">"));
+        assert!(html.contains("<p>Bought it? https://example.com/claim/abc</p>"));
+    }
+}
@@ -1,2 +1,300 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+
+use crate::{
+    product::{tag_expression_matches, Product},
+    Notifier, NotifyError,
+};
+
+use push::Priority;
+use template::Channel;
+
+pub mod budget;
 pub mod discord;
+pub mod email;
+pub mod mqtt;
+pub mod push;
+pub mod slack;
+pub mod telegram;
+pub mod template;
 pub mod twilio;
+pub mod voice;
+pub mod webhook;
+
+// Set once at startup, from --dry-run or the config
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+// A product found this cycle, with the messages we send about it
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub product: Product,
+    // The full message, used when the product is the only one being sent
+    pub message: String,
+    // A short retailer, product, price and link summary, used when several products are sent together
+    pub summary: String,
+    pub detected_at: DateTime<Local>,
+    // Where the scraper's snapshot was saved, if it took one
+    pub snapshot_path: Option<String>,
+}
+
+impl Alert {
+    // The scraper's snapshot as a file name and standalone HTML file
+    pub fn snapshot_file(&self) -> Option<(String, String)> {
+        let product = &self.product;
+        let snapshot = product.get_offer()?.snapshot.as_ref()?;
+        let file_name = format!(
+            "{}-{}-{}.html",
+            self.detected_at.format("%Y%m%d-%H%M%S"),
+            product.to_key(),
+            product.listing_id().unwrap_or_default()
+        );
+        let contents = format!(
+            "<!-- {} {} -->\n<!-- {} -->\n<!-- Detected at {} -->\n<!-- Marker: {} -->\n{}\n",
+            product.retailer_name(),
+            product.get_name().unwrap_or(""),
+            product.get_url().unwrap_or(""),
+            self.detected_at.to_rfc3339(),
+            snapshot.marker,
+            snapshot.excerpt
+        );
+
+        Some((file_name, contents))
+    }
+}
+
+// A delivery we gave up on, reported at the end of the cycle
+#[derive(Debug)]
+pub struct DeliveryFailure {
+    pub channel: Channel,
+    // Where it was going, without any secrets
+    pub target: String,
+    pub products: Vec<String>,
+    pub error: NotifyError,
+}
+
+// Everything that happened trying to reach one subscriber
+#[derive(Debug, Default)]
+pub struct SubscriberDelivery {
+    pub subscriber_id: String,
+    pub texts: Vec<twilio::TextRecord>,
+    pub failures: Vec<DeliveryFailure>,
+    // Set aside from the budget for the texts, handed back once they're recorded
+    pub reserved: f64,
+}
+
+impl DeliveryFailure {
+    pub fn new(channel: Channel, target: String, alerts: &[&Alert], error: NotifyError) -> Self {
+        DeliveryFailure {
+            channel,
+            target,
+            products: product_names(alerts),
+            error,
+        }
+    }
+}
+
+// The retailer and name of each product, for logs
+pub fn product_names(alerts: &[&Alert]) -> Vec<String> {
+    alerts
+        .iter()
+        .map(|alert| match alert.product.get_name() {
+            Ok(name) => format!("{} {}", alert.product.retailer_name(), name),
+            Err(_) => alert.product.retailer_name().to_string(),
+        })
+        .collect()
+}
+
+impl Notifier {
+    // Whether the product has one of the tags configured as high priority
+    pub fn is_high_priority(&self, product: &Product) -> bool {
+        self.config
+            .application_config
+            .high_priority_tags
+            .iter()
+            .flatten()
+            .any(|expr| tag_expression_matches(expr, product.get_tags()))
+    }
+
+    pub fn alert_priority(&self, alerts: &[&Alert]) -> Priority {
+        if alerts
+            .iter()
+            .any(|alert| self.is_high_priority(&alert.product))
+        {
+            Priority::High
+        } else {
+            Priority::Normal
+        }
+    }
+
+    pub fn build_alert(&self, product: &Product) -> Alert {
+        let mut summary = match (product.get_name(), product.get_url()) {
+            (Ok(name), Ok(url)) => {
+                let price = product
+                    .get_offer()
+                    .and_then(|offer| offer.price())
+                    .map(|price| format!(" - ${:.2}", price))
+                    .unwrap_or_default();
+                format!("{}: {}{}\n{}", product.retailer_name(), name, price, url)
+            }
+            _ => product.new_stock_message(),
+        };
+        if let Some(also_check) = self.also_check(product) {
+            summary.push('\n');
+            summary.push_str(&also_check);
+        }
+
+        Alert {
+            product: product.clone(),
+            message: self.stock_message(product),
+            summary,
+            detected_at: product
+                .get_offer()
+                .and_then(|offer| offer.detected_at)
+                .unwrap_or_else(Local::now),
+            snapshot_path: None,
+        }
+    }
+
+    // Save the alert's snapshot to the snapshot directory, returning where it went
+    pub async fn save_snapshot(&self, alert: &Alert) -> Option<String> {
+        let (file_name, contents) = alert.snapshot_file()?;
+        let dir = self.config.application_config.snapshot_dir();
+        let path = format!("{}/{}", dir.trim_end_matches('/'), file_name);
+        if is_dry_run() {
+            println!("[dry run] Snapshot not saved to {}", path);
+            return None;
+        }
+
+        let saved = async {
+            tokio::fs::create_dir_all(dir).await?;
+            tokio::fs::write(&path, contents).await
+        };
+        match saved.await {
+            Ok(()) => Some(path),
+            Err(e) => {
+                eprintln!("Couldn't save snapshot to {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    // Keep track of a subscriber's delivery, returning whether every channel got through
+    pub fn record_delivery(&mut self, delivery: SubscriberDelivery) -> bool {
+        let delivered = delivery.failures.is_empty();
+        if !delivery.texts.is_empty() {
+            self.record_texts(&delivery.subscriber_id, delivery.texts);
+        }
+        self.release_spend(delivery.reserved);
+        for failure in delivery.failures {
+            eprintln!(
+                "{:?} to {} failed: {}",
+                failure.channel, failure.target, failure.error
+            );
+            self.delivery_failures.push(failure);
+        }
+
+        delivered
+    }
+
+    // Print everything that couldn't be delivered this cycle, then start the next one fresh
+    pub fn report_delivery_failures(&mut self) {
+        if self.delivery_failures.is_empty() {
+            return;
+        }
+
+        println!("Failed Deliveries:");
+        for failure in self.delivery_failures.drain(..) {
+            println!(
+                "[{:?}] {}: {} ({})",
+                failure.channel,
+                failure.target,
+                failure.error,
+                failure.products.join(", ")
+            );
+        }
+    }
+}
+
+// How long a notification service gets to answer before we give up on the request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+// A client that can't hold up the whole cycle when a service stops responding
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+}
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+// In a dry run, log what would have been sent and return true so the caller skips sending it
+pub fn dry_run(destination: &str, payload: &str) -> bool {
+    if is_dry_run() {
+        println!("[dry run] {}:\n{}", destination, payload);
+    }
+    is_dry_run()
+}
+
+// Combine everything found in a cycle into one message. A lone alert keeps its full message
+pub fn combined_message(alerts: &[&Alert]) -> String {
+    match alerts {
+        [alert] => alert.message.clone(),
+        _ => format!(
+            "Found {} products:\n\n{}",
+            alerts.len(),
+            alerts
+                .iter()
+                .map(|alert| alert.summary.as_str())
+                .collect::<Vec<&str>>()
+                .join("\n\n")
+        ),
+    }
+}
+
+// Split a message into parts no longer than `max_length` characters, breaking between paragraphs where possible
+pub fn split_message(message: &str, max_length: usize) -> Vec<String> {
+    let max_length = max_length.max(1);
+    let mut parts = vec![];
+    let mut current = String::new();
+
+    for paragraph in message.split("\n\n") {
+        let separator = if current.is_empty() { 0 } else { 2 };
+        if current.chars().count() + separator + paragraph.chars().count() <= max_length {
+            if !current.is_empty() {
+                current.push_str("\n\n");
+            }
+            current.push_str(paragraph);
+            continue;
+        }
+
+        if !current.is_empty() {
+            parts.push(std::mem::take(&mut current));
+        }
+
+        // A paragraph that doesn't fit on its own gets cut wherever it needs to be
+        let chars = paragraph.chars().collect::<Vec<char>>();
+        let mut chunks = chars.chunks(max_length).peekable();
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_some() {
+                parts.push(chunk.iter().collect());
+            } else {
+                current = chunk.iter().collect();
+            }
+        }
+    }
+
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
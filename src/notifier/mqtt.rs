@@ -0,0 +1,246 @@
+use chrono::Local;
+use rumqttc::{AsyncClient, LastWill, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    notifier::{dry_run, is_dry_run, Alert},
+    product::Product,
+    NotifyError,
+};
+
+const DEFAULT_TOPIC_PREFIX: &str = "rtx-notifier";
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Defaults to rtx-notifier
+    pub client_id: Option<String>,
+    // Prefix for our own topics, defaults to rtx-notifier
+    pub topic_prefix: Option<String>,
+    // Home Assistant's discovery prefix, defaults to homeassistant
+    pub discovery_prefix: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: AsyncClient,
+    topic_prefix: String,
+    discovery_prefix: String,
+}
+
+impl MqttPublisher {
+    // Connect to the broker. The connection is driven in the background and reconnects on its own
+    pub fn connect(config: &MqttConfig) -> Self {
+        let client_id = config
+            .client_id
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TOPIC_PREFIX.to_string());
+        let topic_prefix = config
+            .topic_prefix
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TOPIC_PREFIX.to_string());
+
+        // If we drop off, Home Assistant marks every product unavailable
+        let mut last_will = LastWill::new(
+            format!("{}/status", topic_prefix),
+            QoS::AtLeastOnce,
+            "offline",
+        );
+        last_will.retain = true;
+
+        let mut options = MqttOptions::new(client_id, &config.host, config.port.unwrap_or(1883));
+        options
+            .set_keep_alive(30)
+            // Enough room to queue a state for every product in a cycle without waiting on the broker
+            .set_request_channel_capacity(512);
+        // The broker would publish it for us when a dry run exits
+        if !is_dry_run() {
+            options.set_last_will(last_will);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 512);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = eventloop.poll().await {
+                    eprintln!("MQTT connection error: {}", e);
+                    tokio::time::delay_for(std::time::Duration::from_secs(5)).await;
+                }
+            }
+        });
+
+        MqttPublisher {
+            client,
+            topic_prefix,
+            discovery_prefix: config
+                .discovery_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_DISCOVERY_PREFIX.to_string()),
+        }
+    }
+
+    // Announce the products in the background, so a slow or missing broker can't hold up or stop startup
+    pub fn spawn_discovery(&self, products: Vec<Product>) {
+        let publisher = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = publisher.publish_discovery(&products).await {
+                eprintln!("Publishing MQTT discovery failed: {}", e);
+            }
+        });
+    }
+
+    // Publish retained Home Assistant discovery configs so every product shows up as a binary sensor
+    pub async fn publish_discovery(&self, products: &[Product]) -> Result<(), NotifyError> {
+        self.publish(&format!("{}/status", self.topic_prefix), "online", true)
+            .await?;
+
+        for product in products {
+            let id = match product.listing_id() {
+                Some(id) => id,
+                None => continue,
+            };
+            let unique_id = format!("rtx_notifier_{}_{}", product.to_key(), id);
+            let config = json!({
+                "name": format!("{} {}", product.retailer_name(), product.get_name().unwrap_or("")),
+                "unique_id": unique_id,
+                "state_topic": self.state_topic(product.to_key(), &id),
+                "availability_topic": format!("{}/status", self.topic_prefix),
+                "payload_on": "ON",
+                "payload_off": "OFF",
+                "json_attributes_topic": self.attributes_topic(product.to_key(), &id),
+                "device": {
+                    "identifiers": [self.topic_prefix],
+                    "name": "RTX Notifier",
+                },
+            });
+            self.publish(
+                &format!(
+                    "{}/binary_sensor/{}/config",
+                    self.discovery_prefix, unique_id
+                ),
+                &config.to_string(),
+                true,
+            )
+            .await?;
+            self.publish(
+                &self.attributes_topic(product.to_key(), &id),
+                &json!({
+                    "provider": product.to_key(),
+                    "url": product.get_url().unwrap_or(""),
+                    "tags": product.get_tags(),
+                })
+                .to_string(),
+                true,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    // Publish the availability of every product checked this cycle
+    pub async fn publish_states<'a>(
+        &self,
+        in_stock: impl IntoIterator<Item = &'a Product>,
+        out_of_stock: impl IntoIterator<Item = &'a Product>,
+    ) -> Result<(), NotifyError> {
+        let states = in_stock
+            .into_iter()
+            .map(|product| (product, "ON"))
+            .chain(out_of_stock.into_iter().map(|product| (product, "OFF")));
+        for (product, state) in states {
+            if let Some(id) = product.listing_id() {
+                self.publish(&self.state_topic(product.to_key(), &id), state, true)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Publish a restock event for everything found this cycle
+    pub async fn publish_restocks(&self, alerts: &[&Alert]) -> Result<(), NotifyError> {
+        for alert in alerts {
+            let product = &alert.product;
+            let event = json!({
+                "provider": product.to_key(),
+                "product_id": product.listing_id(),
+                "product": product.get_name().ok(),
+                "url": product.get_url().ok(),
+                "price": product.get_offer().and_then(|offer| offer.price()),
+                "message": alert.message,
+                "snapshot": alert.snapshot_path,
+                "timestamp": Local::now().to_rfc3339(),
+            });
+            let topic = format!("{}/restock", self.topic_prefix);
+            self.publish(&topic, &event.to_string(), false).await?;
+        }
+
+        Ok(())
+    }
+
+    fn state_topic(&self, key: &str, id: &str) -> String {
+        format!("{}/{}/{}/state", self.topic_prefix, key, id)
+    }
+
+    fn attributes_topic(&self, key: &str, id: &str) -> String {
+        format!("{}/{}/{}/attributes", self.topic_prefix, key, id)
+    }
+
+    // Every message goes through here, so a dry run publishes nothing
+    async fn publish(&self, topic: &str, payload: &str, retain: bool) -> Result<(), NotifyError> {
+        if dry_run(&format!("MQTT topic {}", topic), payload) {
+            return Ok(());
+        }
+        self.client
+            .publish(topic, QoS::AtLeastOnce, retain, payload.as_bytes().to_vec())
+            .await
+            .map_err(|e| NotifyError::MqttPublish(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{self, MqttBroker};
+
+    #[tokio::test]
+    async fn announces_products_and_their_state() {
+        let broker = MqttBroker::start().await;
+        let publisher = MqttPublisher::connect(&MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(broker.port),
+            username: None,
+            password: None,
+            client_id: None,
+            topic_prefix: None,
+            discovery_prefix: None,
+        });
+        let product = stub::product("RTX 3080");
+
+        publisher.spawn_discovery(vec![product.clone()]);
+        // Discovery goes out first, states only once it's done
+        broker.published(3).await;
+        publisher.publish_states(&[product], &[]).await.unwrap();
+        let published = broker.published(4).await;
+
+        assert_eq!(published.len(), 4);
+        assert_eq!(published[0].topic, "rtx-notifier/status");
+        assert_eq!(published[0].payload, "online");
+        let config: serde_json::Value = serde_json::from_str(&published[1].payload).unwrap();
+        assert!(published[1]
+            .topic
+            .starts_with("homeassistant/binary_sensor/rtx_notifier_bestbuy_"));
+        assert!(published[1].retain);
+        assert_eq!(config["state_topic"], published[3].topic);
+        assert_eq!(config["device"]["identifiers"][0], "rtx-notifier");
+        assert_eq!(published[3].payload, "ON");
+    }
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    notifier::{dry_run, http_client, Alert},
+    NotifyError,
+};
+
+const PUSHOVER_API_URL: &str = "https://api.pushover.net/1/messages.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PushTarget {
+    Ntfy {
+        // The full topic URL, like "https://ntfy.sh/my-rtx-alerts"
+        url: String,
+        // Access token for protected topics
+        token: Option<String>,
+    },
+    Gotify {
+        server: String,
+        app_token: String,
+    },
+    Pushover {
+        app_token: String,
+        user_key: String,
+        // Defaults to the public Pushover API
+        api_url: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Priority {
+    // Any in stock alert. Still loud, these are why we're running
+    Normal,
+    // In stock alerts for high priority tags, as loud as each service allows
+    High,
+}
+
+pub async fn send_push(
+    target: &PushTarget,
+    message: &str,
+    alerts: &[&Alert],
+    priority: Priority,
+) -> Result<(), NotifyError> {
+    let client = http_client();
+    let title = match alerts {
+        [alert] => format!("{} has stock", alert.product.retailer_name()),
+        [] => "RTX Notifier".to_string(),
+        _ => format!("Found {} products", alerts.len()),
+    };
+    // Tapping the notification opens the product when there's only one
+    let click_url = match alerts {
+        [alert] => alert.product.get_url().ok(),
+        _ => None,
+    };
+
+    let service = match target {
+        PushTarget::Ntfy { url, .. } => url.as_str(),
+        PushTarget::Gotify { server, .. } => server.as_str(),
+        PushTarget::Pushover { .. } => "Pushover",
+    };
+    if dry_run(
+        &format!("{:?} priority push on {}", priority, service),
+        &format!("{}\n{}", title, message),
+    ) {
+        return Ok(());
+    }
+
+    let request = match target {
+        PushTarget::Ntfy { url, token } => {
+            let mut request = client
+                .post(url)
+                .header("X-Title", title.as_str())
+                .header(
+                    "X-Priority",
+                    match priority {
+                        Priority::High => "5",
+                        Priority::Normal => "4",
+                    },
+                )
+                .body(message.to_string());
+            if let Some(click_url) = click_url {
+                request = request.header("X-Click", click_url);
+            }
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            request
+        }
+        PushTarget::Gotify { server, app_token } => {
+            let mut body = json!({
+                "title": title,
+                "message": message,
+                "priority": match priority {
+                    Priority::High => 10,
+                    Priority::Normal => 8,
+                },
+            });
+            if let Some(click_url) = click_url {
+                body["extras"] =
+                    json!({ "client::notification": { "click": { "url": click_url } } });
+            }
+            client
+                .post(&format!("{}/message", server.trim_end_matches('/')))
+                .header("X-Gotify-Key", app_token.as_str())
+                .header("Content-Type", "application/json")
+                .body(body.to_string())
+        }
+        PushTarget::Pushover {
+            app_token,
+            user_key,
+            api_url,
+        } => {
+            let mut form = vec![
+                ("token", app_token.to_string()),
+                ("user", user_key.to_string()),
+                ("title", title.clone()),
+                ("message", message.to_string()),
+            ];
+            match priority {
+                // Emergency priority repeats until acknowledged, and needs to know how often and for how long
+                Priority::High => form.extend(vec![
+                    ("priority", "2".to_string()),
+                    ("retry", "60".to_string()),
+                    ("expire", "1800".to_string()),
+                ]),
+                Priority::Normal => form.push(("priority", "1".to_string())),
+            }
+            if let Some(click_url) = click_url {
+                form.push(("url", click_url.to_string()));
+            }
+            client
+                .post(api_url.as_deref().unwrap_or(PUSHOVER_API_URL))
+                .form(&form)
+        }
+    };
+
+    let res = request
+        .send()
+        .await
+        .map_err(NotifyError::WebRequestFailed)?;
+    let status = res.status();
+    if status.as_u16() == 429 {
+        return Err(NotifyError::RateLimit);
+    }
+    if !status.is_success() {
+        return Err(NotifyError::WebClient(status));
+    }
+
+    println!(
+        "Sent [{}] push notification at {:?} priority",
+        message, priority
+    );
+
+    Ok(())
+}
@@ -0,0 +1,232 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifier::{dry_run, http_client, Alert},
+    NotifyError,
+};
+
+// Slack allows 50 blocks per message and each product takes two
+const MAX_ALERTS: usize = 25;
+// How many times we'll wait out a 429 before giving up on a message
+const MAX_RATELIMIT_RETRIES: usize = 3;
+// The longest we'll hold up the rest of the alerts waiting out a 429
+const MAX_RETRY_AFTER_SECS: u64 = 10;
+
+pub async fn send_webhook(alerts: &[&Alert], url: &str) -> Result<(), NotifyError> {
+    let client = http_client();
+    for chunk in alerts.chunks(MAX_ALERTS) {
+        let webhook_body = SlackWebhook {
+            text: format!("Found {} products", chunk.len()),
+            blocks: chunk.iter().flat_map(|alert| alert_blocks(alert)).collect(),
+        };
+
+        let payload = serde_json::to_string(&webhook_body).unwrap();
+        if dry_run("Slack webhook", &payload) {
+            continue;
+        }
+
+        let mut retries = 0;
+        loop {
+            let res = client
+                .post(url)
+                .body(payload.clone())
+                .header("Content-Type", "application/json")
+                .send()
+                .await
+                .map_err(NotifyError::WebRequestFailed)?;
+
+            let status = res.status();
+            // Slack tells us how many seconds to back off for when we're sending too fast
+            if status.as_u16() == 429 {
+                let retry_after = res
+                    .headers()
+                    .get("Retry-After")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or(1);
+                if retries >= MAX_RATELIMIT_RETRIES || retry_after > MAX_RETRY_AFTER_SECS {
+                    return Err(NotifyError::RateLimit);
+                }
+                println!("Slack ratelimited us, retrying in {}s", retry_after);
+                tokio::time::delay_for(std::time::Duration::from_secs(retry_after)).await;
+                retries += 1;
+                continue;
+            }
+
+            if !status.is_success() {
+                return Err(NotifyError::WebClient(status));
+            }
+
+            break;
+        }
+
+        println!(
+            "Sent slack webhook with {} products\nPayload: {}",
+            chunk.len(),
+            payload
+        );
+    }
+
+    Ok(())
+}
+
+// The message, templated or not, with the retailer and price alongside it
+fn alert_blocks(alert: &Alert) -> Vec<Block> {
+    let product = &alert.product;
+    let mut fields = vec![Text::mrkdwn(format!(
+        "*Retailer*\n{}",
+        product.retailer_name()
+    ))];
+    if let Some(price) = product.get_offer().and_then(|offer| offer.price()) {
+        fields.push(Text::mrkdwn(format!("*Price*\n${:.2}", price)));
+    }
+
+    vec![
+        Block::Section {
+            text: Text::mrkdwn(alert.message.clone()),
+            fields: Some(fields),
+            accessory: product.get_url().ok().map(|url| Element::Button {
+                text: Text::plain("Open"),
+                url: url.to_string(),
+                style: Some("primary".to_string()),
+            }),
+        },
+        Block::Divider,
+    ]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SlackWebhook {
+    // Shown in notifications where blocks can't be rendered
+    text: String,
+    blocks: Vec<Block>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Block {
+    Section {
+        text: Text,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fields: Option<Vec<Text>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        accessory: Option<Element>,
+    },
+    Divider,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Element {
+    Button {
+        text: Text,
+        url: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        style: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Text {
+    #[serde(rename = "type")]
+    kind: String,
+    text: String,
+}
+
+impl Text {
+    fn mrkdwn(text: String) -> Self {
+        Self {
+            kind: "mrkdwn".to_string(),
+            text,
+        }
+    }
+
+    fn plain(text: &str) -> Self {
+        Self {
+            kind: "plain_text".to_string(),
+            text: text.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::stub::{self, HttpStub, Reply};
+
+    #[tokio::test]
+    async fn posts_blocks_with_an_open_button() {
+        let slack = HttpStub::start(|_| Reply::ok());
+        let alert = stub::alert(stub::product("RTX 3080"));
+
+        send_webhook(&[&alert], &format!("{}/hook", slack.url))
+            .await
+            .unwrap();
+
+        let requests = slack.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/hook");
+        let body = requests[0].json();
+        assert_eq!(body["text"], "Found 1 products");
+        assert_eq!(body["blocks"][0]["type"], "section");
+        assert_eq!(
+            body["blocks"][0]["accessory"]["url"],
+            "https://www.bestbuy.com/site/RTX-3080"
+        );
+        assert_eq!(body["blocks"][0]["text"]["text"], alert.message);
+        assert_eq!(body["blocks"][0]["fields"][1]["text"], "*Price*\n$699.99");
+    }
+
+    #[tokio::test]
+    async fn waits_out_rate_limits() {
+        let calls = AtomicUsize::new(0);
+        let slack = HttpStub::start(move |_| {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                Reply {
+                    status: 429,
+                    headers: vec![("Retry-After", "0".to_string())],
+                    body: "rate_limited".to_string(),
+                }
+            } else {
+                Reply::ok()
+            }
+        });
+        let alert = stub::alert(stub::product("RTX 3080"));
+
+        send_webhook(&[&alert], &slack.url).await.unwrap();
+
+        assert_eq!(slack.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_repeated_rate_limits() {
+        let slack = HttpStub::start(|_| Reply {
+            status: 429,
+            headers: vec![("Retry-After", "0".to_string())],
+            body: "rate_limited".to_string(),
+        });
+        let alert = stub::alert(stub::product("RTX 3080"));
+
+        let result = send_webhook(&[&alert], &slack.url).await;
+
+        assert!(matches!(result, Err(NotifyError::RateLimit)));
+        assert_eq!(slack.requests().len(), MAX_RATELIMIT_RETRIES + 1);
+    }
+
+    #[tokio::test]
+    async fn long_rate_limits_are_not_waited_out() {
+        let slack = HttpStub::start(|_| Reply {
+            status: 429,
+            headers: vec![("Retry-After", "3600".to_string())],
+            body: "rate_limited".to_string(),
+        });
+        let alert = stub::alert(stub::product("RTX 3080"));
+
+        let result = send_webhook(&[&alert], &slack.url).await;
+
+        assert!(matches!(result, Err(NotifyError::RateLimit)));
+        assert_eq!(slack.requests().len(), 1);
+    }
+}
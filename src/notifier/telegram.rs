@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifier::{dry_run, http_client, split_message, Alert},
+    NotifyError,
+};
+
+pub const DEFAULT_API_URL: &str = "https://api.telegram.org";
+// Telegram refuses message text longer than this
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+pub async fn send_message(
+    api_url: &str,
+    token: &str,
+    chat_id: i64,
+    message: &str,
+    alerts: &[&Alert],
+) -> Result<(), NotifyError> {
+    let client = http_client();
+    let url = format!("{}/bot{}/sendMessage", api_url.trim_end_matches('/'), token);
+
+    let parts = split_message(message, MAX_MESSAGE_LENGTH);
+    let last = parts.len().saturating_sub(1);
+    for (i, part) in parts.into_iter().enumerate() {
+        // Only the last part gets the buttons, so they end up under the whole message
+        let reply_markup = if i == last && !alerts.is_empty() {
+            Some(InlineKeyboardMarkup {
+                inline_keyboard: alerts
+                    .iter()
+                    .filter_map(|alert| link_button(alert))
+                    .map(|button| vec![button])
+                    .collect(),
+            })
+        } else {
+            None
+        };
+
+        let body = SendMessage {
+            chat_id,
+            text: part,
+            disable_web_page_preview: true,
+            reply_markup,
+        };
+
+        let payload = serde_json::to_string(&body).unwrap();
+        if dry_run(&format!("Telegram chat {}", chat_id), &payload) {
+            continue;
+        }
+        let res = client
+            .post(&url)
+            .body(payload)
+            .header("Content-Type", "application/json")
+            .send()
+            .await
+            .map_err(NotifyError::WebRequestFailed)?;
+
+        let status = res.status();
+        if status.as_u16() == 429 {
+            return Err(NotifyError::RateLimit);
+        }
+        if !status.is_success() {
+            return Err(NotifyError::WebClient(status));
+        }
+
+        println!("Sent [{}] telegram message to {}", body.text, chat_id);
+    }
+
+    Ok(())
+}
+
+fn link_button(alert: &Alert) -> Option<InlineKeyboardButton> {
+    let url = alert.product.get_url().ok()?;
+    let text = match alert.product.get_name() {
+        Ok(name) => format!("{}: {}", alert.product.retailer_name(), name),
+        Err(_) => alert.product.retailer_name().to_string(),
+    };
+
+    Some(InlineKeyboardButton {
+        text,
+        url: url.to_string(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SendMessage {
+    chat_id: i64,
+    text: String,
+    disable_web_page_preview: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct InlineKeyboardMarkup {
+    inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct InlineKeyboardButton {
+    text: String,
+    url: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{self, HttpStub, Reply};
+
+    #[tokio::test]
+    async fn sends_message_with_link_buttons() {
+        let telegram = HttpStub::start(|_| Reply::json(200, serde_json::json!({ "ok": true })));
+        let alert = stub::alert(stub::product("RTX 3080"));
+
+        send_message(&telegram.url, "token", 42, &alert.message, &[&alert])
+            .await
+            .unwrap();
+
+        let requests = telegram.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/bottoken/sendMessage");
+        let body = requests[0].json();
+        assert_eq!(body["chat_id"], 42);
+        assert_eq!(body["text"], alert.message);
+        let button = &body["reply_markup"]["inline_keyboard"][0][0];
+        assert_eq!(button["text"], "Bestbuy: RTX 3080");
+        assert_eq!(button["url"], "https://www.bestbuy.com/site/RTX-3080");
+    }
+
+    #[tokio::test]
+    async fn buttons_go_on_the_last_part() {
+        let telegram = HttpStub::start(|_| Reply::json(200, serde_json::json!({ "ok": true })));
+        let alert = stub::alert(stub::product("RTX 3080"));
+        let message = "x".repeat(MAX_MESSAGE_LENGTH + 10);
+
+        send_message(&telegram.url, "token", 42, &message, &[&alert])
+            .await
+            .unwrap();
+
+        let requests = telegram.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].json().get("reply_markup").is_none());
+        assert!(requests[1].json().get("reply_markup").is_some());
+    }
+
+    #[tokio::test]
+    async fn reports_rate_limits() {
+        let telegram = HttpStub::start(|_| Reply::json(429, serde_json::json!({ "ok": false })));
+
+        let result = send_message(&telegram.url, "token", 42, "hello", &[]).await;
+
+        assert!(matches!(result, Err(NotifyError::RateLimit)));
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use minijinja::Environment;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifier::{combined_message, email, Alert},
+    subscriber::Subscriber,
+    Notifier, NotifyError,
+};
+
+// Templates under this locale are used when there isn't one for the reader's locale
+const DEFAULT_LOCALE: &str = "default";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Sms,
+    Discord,
+    Slack,
+    Telegram,
+    Email,
+    Push,
+}
+
+impl Channel {
+    // Email templates are HTML, so anything put into them is escaped
+    fn template_name(self) -> &'static str {
+        match self {
+            Channel::Sms => "sms",
+            Channel::Discord => "discord",
+            Channel::Slack => "slack",
+            Channel::Telegram => "telegram",
+            Channel::Email => "email.html",
+            Channel::Push => "push",
+        }
+    }
+}
+
+// Templates for each product in an alert, keyed by channel and then by locale like "en" or "de-AT"
+pub type Templates = HashMap<Channel, HashMap<String, String>>;
+
+// Everything a template can use
+#[derive(Serialize)]
+struct TemplateContext<'a> {
+    retailer: &'a str,
+    provider: &'a str,
+    product: Option<&'a str>,
+    // Formatted like "$699.99", when the retailer showed one
+    price: Option<String>,
+    link: Option<&'a str>,
+    seller: Option<&'a str>,
+    detected_at: String,
+    in_stock: bool,
+    stock_status: &'a str,
+}
+
+// Make sure every configured template parses, so a typo shows up at startup instead of mid restock
+pub fn validate(templates: &Templates) -> Result<(), NotifyError> {
+    let env = Environment::new();
+    for (channel, locales) in templates {
+        for template in locales.values() {
+            env.template_from_named_str(channel.template_name(), template)
+                .map_err(|e| NotifyError::Template(Box::new(e)))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn find_template<'a>(
+    templates: &'a Templates,
+    channel: Channel,
+    locale: Option<&str>,
+) -> Option<&'a str> {
+    let locales = templates.get(&channel)?;
+    // Try the exact locale, then its language, so "de-AT" can use a "de" template
+    let language = locale.and_then(|locale| locale.split(['-', '_']).next());
+
+    locale
+        .into_iter()
+        .chain(language)
+        .chain(Some(DEFAULT_LOCALE))
+        .find_map(|locale| locales.get(locale))
+        .map(String::as_str)
+}
+
+impl Notifier {
+    // The subscriber's locale, or the configured default
+    pub fn locale<'a>(&'a self, subscriber: Option<&'a Subscriber>) -> Option<&'a str> {
+        subscriber
+            .and_then(|subscriber| subscriber.locale.as_deref())
+            .or(self.config.application_config.locale.as_deref())
+    }
+
+    // Alerts with their messages rendered from the channel's template, if it has one
+    pub fn templated_alerts(
+        &self,
+        channel: Channel,
+        locale: Option<&str>,
+        alerts: &[&Alert],
+    ) -> Option<Vec<Alert>> {
+        let templates = self.config.application_config.templates.as_ref()?;
+        let template = find_template(templates, channel, locale)?;
+        if alerts.is_empty() {
+            return None;
+        }
+
+        let env = Environment::new();
+        let template = match env.template_from_named_str(channel.template_name(), template) {
+            Ok(template) => template,
+            Err(e) => {
+                eprintln!("Couldn't load the {:?} template: {}", channel, e);
+                return None;
+            }
+        };
+
+        let mut rendered = vec![];
+        for alert in alerts {
+            let product = &alert.product;
+            let offer = product.get_offer();
+            let context = TemplateContext {
+                retailer: product.retailer_name(),
+                provider: product.to_key(),
+                product: product.get_name().ok(),
+                price: offer
+                    .and_then(|offer| offer.price())
+                    .map(|price| format!("${:.2}", price)),
+                link: product.get_url().ok(),
+                seller: offer.and_then(|offer| offer.seller.as_deref()),
+                detected_at: alert.detected_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                in_stock: true,
+                stock_status: "in stock",
+            };
+
+            match template.render(context) {
+                Ok(mut message) => {
+                    // Other listings of the card go after the template, so they can't be left out
+                    if let Some(also_check) = self.also_check(product) {
+                        match channel {
+                            Channel::Email => message.push_str(&email::paragraph(&also_check)),
+                            _ => {
+                                message.push('\n');
+                                message.push_str(&also_check);
+                            }
+                        }
+                    }
+                    rendered.push(Alert {
+                        summary: message.clone(),
+                        message,
+                        ..(*alert).clone()
+                    })
+                }
+                // Fall back to the usual message rather than dropping the alert
+                Err(e) => {
+                    eprintln!("Couldn't render the {:?} template: {}", channel, e);
+                    rendered.push((*alert).clone());
+                }
+            }
+        }
+
+        Some(rendered)
+    }
+
+    // The alerts to send on a channel, templated where there's a template
+    pub fn channel_alerts(
+        &self,
+        channel: Channel,
+        locale: Option<&str>,
+        alerts: &[&Alert],
+    ) -> Vec<Alert> {
+        self.templated_alerts(channel, locale, alerts)
+            .unwrap_or_else(|| alerts.iter().map(|alert| (*alert).clone()).collect())
+    }
+
+    // The message and alerts to send on a channel. Without a template the message is left alone
+    pub fn channel_message(
+        &self,
+        channel: Channel,
+        locale: Option<&str>,
+        message: &str,
+        alerts: &[&Alert],
+    ) -> (String, Vec<Alert>) {
+        match self.templated_alerts(channel, locale, alerts) {
+            Some(rendered) => (
+                combined_message(&rendered.iter().collect::<Vec<&Alert>>()),
+                rendered,
+            ),
+            None => (
+                message.to_string(),
+                alerts.iter().map(|alert| (*alert).clone()).collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::product::{Offer, Product, ProductDetails};
+    use crate::stub;
+
+    #[test]
+    fn other_listings_follow_the_template() {
+        let mut notifier = stub::notifier(
+            serde_json::json!({
+                "templates": { "telegram": { "default": "{{ product }} for {{ price }}" } },
+            }),
+            serde_json::json!([]),
+        );
+        let details = |page: &str| ProductDetails {
+            product: "RTX 3080".to_string(),
+            page: page.to_string(),
+            catalog_id: Some("3080-fe".to_string()),
+            ..ProductDetails::default()
+        };
+        let bestbuy =
+            Product::BestBuy(details("https://www.bestbuy.com/site/3080")).with_offer(Offer {
+                price_cents: Some(69_999),
+                ..Offer::default()
+            });
+        let newegg = Product::NewEgg(Some(details("https://www.newegg.com/p/3080")));
+        notifier.config.products = vec![bestbuy.clone(), newegg];
+        let alert = stub::alert(bestbuy);
+
+        let (message, _) = notifier.channel_message(Channel::Telegram, None, "", &[&alert]);
+
+        assert_eq!(
+            message,
+            "RTX 3080 for $699.99\nAlso check:\nnewegg: https://www.newegg.com/p/3080"
+        );
+    }
+}
@@ -1,28 +1,623 @@
-use twilio::OutboundMessage;
+use std::fmt;
 
-use crate::{product::Product, NotifyError, Subscriber};
+use chrono::{DateTime, Duration, Local};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::Mutex;
 
+use crate::{
+    notifier::{dry_run, http_client, split_message},
+    Notifier, NotifyError,
+};
+
+// Twilio refuses message bodies longer than this
+pub const MAX_MESSAGE_LENGTH: usize = 1600;
+// Twilio takes at most this many images per message
+const MAX_MEDIA: usize = 10;
+pub const DEFAULT_API_URL: &str = "https://api.twilio.com";
+// How many texts we remember per subscriber
+const MAX_LOGGED_TEXTS: usize = 50;
+// Texts older than this are left alone when polling, Twilio has long since settled on a status
+const POLL_WINDOW_HOURS: i64 = 24;
+
+// Error codes that mean the number will never take a text from us, like invalid, landline or opted out numbers
+// https://www.twilio.com/docs/api/errors
+const PERMANENT_ERROR_CODES: &[u32] = &[
+    21211, 21214, 21217, 21407, 21421, 21610, 21612, 21614, 30004, 30005, 30006,
+];
+
+// Texts in these states may still change, so they're worth polling
+const PENDING_STATUSES: &[&str] = &["accepted", "queued", "sending", "sent"];
+
+// How a subscriber wants their texts
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TextChannel {
+    #[default]
+    Sms,
+    // Texts with the product images attached
+    Mms,
+    // Needs a WhatsApp enabled sender, see `whatsapp_from_number`
+    Whatsapp,
+}
+
+impl TextChannel {
+    // The number as Twilio wants it for this channel
+    pub fn address(self, phone: &str) -> String {
+        match self {
+            TextChannel::Whatsapp => format!("whatsapp:{}", phone),
+            TextChannel::Sms | TextChannel::Mms => phone.to_string(),
+        }
+    }
+
+    // What a part of a split text is billed as, only the first part of an MMS carries the images
+    pub fn billed_as(self, part: usize) -> TextChannel {
+        match self {
+            TextChannel::Mms if part > 0 => TextChannel::Sms,
+            channel => channel,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    account_id: String,
+    auth_token: String,
+    // Configurable so it can be pointed at a mock
+    api_url: String,
+}
+
+// A message as the API describes it
+#[derive(Deserialize, Debug, Clone)]
+pub struct MessageResource {
+    pub sid: String,
+    pub status: String,
+    pub error_code: Option<u32>,
+    pub error_message: Option<String>,
+    // How many segments the body was split into, which is what SMS is billed by
+    pub num_segments: Option<String>,
+}
+
+// A voice call as the API describes it
+#[derive(Deserialize, Debug, Clone)]
+pub struct CallResource {
+    pub sid: String,
+    pub status: String,
+    // Seconds, once the call has ended
+    pub duration: Option<String>,
+}
+
+// The body Twilio sends with any failed request
+#[derive(Deserialize, Debug)]
+pub struct TwilioError {
+    #[serde(skip)]
+    pub status: u16,
+    pub code: Option<u32>,
+    pub message: String,
+}
+
+impl fmt::Display for TwilioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} ({}): {}", self.status, code, self.message),
+            None => write!(f, "{}: {}", self.status, self.message),
+        }
+    }
+}
+
+// A text we sent or tried to send, kept so we know what happened to it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TextRecord {
+    pub to: String,
+    // Missing when Twilio refused the text outright
+    pub sid: Option<String>,
+    pub sent_at: DateTime<Local>,
+    pub status: String,
+    pub error_code: Option<u32>,
+    pub error: Option<String>,
+    // Missing for texts logged before we kept track of spend
+    pub channel: Option<TextChannel>,
+    pub segments: Option<u32>,
+}
+
+impl TextRecord {
+    pub fn sent(to: &str, channel: TextChannel, message: MessageResource) -> Self {
+        TextRecord {
+            to: to.to_string(),
+            sid: Some(message.sid),
+            sent_at: Local::now(),
+            status: message.status,
+            error_code: message.error_code,
+            error: message.error_message,
+            channel: Some(channel),
+            segments: message
+                .num_segments
+                .and_then(|segments| segments.parse().ok()),
+        }
+    }
+
+    pub fn failed(to: &str, error: &NotifyError) -> Self {
+        TextRecord {
+            to: to.to_string(),
+            sid: None,
+            sent_at: Local::now(),
+            status: "failed".to_string(),
+            error_code: match error {
+                NotifyError::TwilioSend(e) => e.code,
+                _ => None,
+            },
+            error: Some(error.to_string()),
+            channel: None,
+            segments: None,
+        }
+    }
+
+    // Whether this text shows the number can't be texted at all
+    pub fn is_permanent_failure(&self) -> bool {
+        match self.error_code {
+            Some(code) => PERMANENT_ERROR_CODES.contains(&code),
+            None => false,
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.sid.is_some()
+            && PENDING_STATUSES.contains(&self.status.as_str())
+            && self.sent_at > Local::now() - Duration::hours(POLL_WINDOW_HOURS)
+    }
+}
+
+impl Client {
+    pub fn new(account_id: &str, auth_token: &str, api_url: &str) -> Self {
+        Client {
+            http: http_client(),
+            account_id: account_id.to_string(),
+            auth_token: auth_token.to_string(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn resource_url(&self, resource: &str) -> String {
+        format!(
+            "{}/2010-04-01/Accounts/{}/{}",
+            self.api_url, self.account_id, resource
+        )
+    }
+
+    pub async fn send_message(
+        &self,
+        from: &str,
+        to: &str,
+        body: &str,
+        media_urls: &[&str],
+    ) -> Result<MessageResource, NotifyError> {
+        let mut form = vec![("From", from), ("To", to), ("Body", body)];
+        form.extend(media_urls.iter().map(|url| ("MediaUrl", *url)));
+        let res = self
+            .http
+            .post(&format!("{}.json", self.resource_url("Messages")))
+            .basic_auth(&self.account_id, Some(&self.auth_token))
+            .form(&form)
+            .send()
+            .await
+            .map_err(NotifyError::WebRequestFailed)?;
+
+        parse_response(res).await
+    }
+
+    pub async fn fetch_message(&self, sid: &str) -> Result<MessageResource, NotifyError> {
+        let res = self
+            .http
+            .get(&format!("{}/{}.json", self.resource_url("Messages"), sid))
+            .basic_auth(&self.account_id, Some(&self.auth_token))
+            .send()
+            .await
+            .map_err(NotifyError::WebRequestFailed)?;
+
+        parse_response(res).await
+    }
+
+    // Call a number and have Twilio follow the TwiML instructions
+    pub async fn create_call(
+        &self,
+        from: &str,
+        to: &str,
+        twiml: &str,
+    ) -> Result<CallResource, NotifyError> {
+        let res = self
+            .http
+            .post(&format!("{}.json", self.resource_url("Calls")))
+            .basic_auth(&self.account_id, Some(&self.auth_token))
+            .form(&[("From", from), ("To", to), ("Twiml", twiml)])
+            .send()
+            .await
+            .map_err(NotifyError::WebRequestFailed)?;
+
+        parse_response(res).await
+    }
+
+    pub async fn fetch_call(&self, sid: &str) -> Result<CallResource, NotifyError> {
+        let res = self
+            .http
+            .get(&format!("{}/{}.json", self.resource_url("Calls"), sid))
+            .basic_auth(&self.account_id, Some(&self.auth_token))
+            .send()
+            .await
+            .map_err(NotifyError::WebRequestFailed)?;
+
+        parse_response(res).await
+    }
+}
+
+async fn parse_response<T: DeserializeOwned>(res: reqwest::Response) -> Result<T, NotifyError> {
+    let status = res.status();
+    let body = res.text().await.map_err(NotifyError::WebRequestFailed)?;
+    if !status.is_success() {
+        let mut error = serde_json::from_str::<TwilioError>(&body).unwrap_or(TwilioError {
+            status: 0,
+            code: None,
+            message: body,
+        });
+        error.status = status.as_u16();
+        return Err(NotifyError::TwilioSend(error));
+    }
+
+    serde_json::from_str(&body).map_err(NotifyError::TwilioResponse)
+}
+
+// Make text safe to put in TwiML
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// A part of a message failing to send, with the parts that went out before it
+#[derive(Debug)]
+pub struct SendFailure {
+    pub sent: Vec<MessageResource>,
+    pub error: NotifyError,
+}
+
+// The texts a message is sent as
+pub fn split_text(message: &str, max_length: usize) -> Vec<String> {
+    split_message(message, max_length.clamp(1, MAX_MESSAGE_LENGTH))
+}
+
+// Send the message, split into as many texts as it takes. Any images go with the first one
+// The numbers are addresses, already prefixed for the channel they're sent on
 pub async fn send_twilio_message(
-    product: &Product,
-    client: &twilio::Client,
-    subscriber: &Subscriber,
+    message: &str,
+    client: &Client,
+    to_phone: &str,
     from_phone: &str,
-) -> Result<(), NotifyError> {
-    let message = &product.new_stock_message();
-    // And send our text message
-    client
-        .send_message(OutboundMessage::new(
-            from_phone, // If this unwrap panics someone (probably Logan), has severely broken the twilio integration
-            &subscriber.to_phone_number,
-            message,
-        ))
+    max_length: usize,
+    media_urls: &[&str],
+) -> Result<Vec<MessageResource>, SendFailure> {
+    let media_urls = &media_urls[..media_urls.len().min(MAX_MEDIA)];
+    let mut sent = vec![];
+    for (i, part) in split_text(message, max_length).into_iter().enumerate() {
+        let media = if i == 0 { media_urls } else { &[] };
+        let destination = match media.len() {
+            0 => format!("Text to {}", to_phone),
+            images => format!("Text to {} with {} images", to_phone, images),
+        };
+        if dry_run(&destination, &part) {
+            continue;
+        }
+        // And send our text message
+        let resource = match client
+            .send_message(from_phone, to_phone, &part, media)
+            .await
+        {
+            Ok(resource) => resource,
+            Err(error) => return Err(SendFailure { sent, error }),
+        };
+
+        println!(
+            "Sent [{}] message to {} as {}",
+            part, to_phone, resource.sid
+        );
+        sent.push(resource);
+    }
+
+    Ok(sent)
+}
+
+impl Notifier {
+    // Remember what happened to a subscriber's texts, and stop texting numbers that can't be reached
+    pub fn record_texts(&mut self, subscriber_id: &str, texts: Vec<TextRecord>) {
+        for text in texts.iter().filter(|text| text.is_permanent_failure()) {
+            self.disable_phone(&text.to, text.error.as_deref().unwrap_or(&text.status));
+        }
+        self.record_text_spend(subscriber_id, &texts);
+
+        let log = self
+            .config
+            .application_config
+            .sms_log
+            .get_or_insert_with(std::collections::HashMap::new)
+            .entry(subscriber_id.to_string())
+            .or_default();
+        log.extend(texts);
+        let overflow = log.len().saturating_sub(MAX_LOGGED_TEXTS);
+        log.drain(..overflow);
+    }
+
+    // Take in what Twilio says happened to a text we sent the subscriber
+    fn update_text(&mut self, id: &str, message: MessageResource) {
+        let text = match self
+            .config
+            .application_config
+            .sms_log
+            .as_mut()
+            .and_then(|log| log.get_mut(id))
+            .and_then(|texts| {
+                texts
+                    .iter_mut()
+                    .find(|text| text.sid.as_deref() == Some(message.sid.as_str()))
+            }) {
+            Some(text) => text,
+            None => return,
+        };
+
+        if text.status != message.status {
+            println!("Text {} to {} is now {}", message.sid, id, message.status);
+        }
+        text.status = message.status;
+        text.error_code = message.error_code;
+        text.error = message.error_message;
+
+        if text.is_permanent_failure() {
+            let (to, reason) = (
+                text.to.clone(),
+                text.error.clone().unwrap_or_else(|| text.status.clone()),
+            );
+            self.disable_phone(&to, &reason);
+        }
+    }
+
+    fn disable_phone(&mut self, phone: &str, reason: &str) {
+        for subscriber in self
+            .config
+            .subscribers
+            .iter_mut()
+            .filter(|subscriber| subscriber.to_phone_number.as_deref() == Some(phone))
+        {
+            if subscriber.sms_disabled.is_none() {
+                eprintln!("Turning off texts to {}: {}", phone, reason);
+                subscriber.sms_disabled = Some(reason.to_string());
+            }
+        }
+    }
+}
+
+// Check on texts Twilio hadn't finished with yet
+// The notifier isn't locked while we wait on Twilio, texts are matched back up by their sid
+pub async fn poll_text_statuses(notifier: &Mutex<Notifier>) {
+    let (client, pending) = {
+        let notifier = notifier.lock().await;
+        let client = match &notifier.twilio {
+            Some(client) if notifier.config.application_config.twilio_poll_status == Some(true) => {
+                client.clone()
+            }
+            _ => return,
+        };
+        let pending = notifier
+            .config
+            .application_config
+            .sms_log
+            .iter()
+            .flatten()
+            .flat_map(|(id, texts)| {
+                texts
+                    .iter()
+                    .filter(|text| text.is_pending())
+                    .map(move |text| (id.clone(), text.sid.clone().unwrap_or_default()))
+            })
+            .collect::<Vec<(String, String)>>();
+        (client, pending)
+    };
+
+    let mut updates = vec![];
+    for (id, sid) in pending {
+        match client.fetch_message(&sid).await {
+            Ok(message) => updates.push((id, message)),
+            Err(e) => eprintln!("Couldn't check on text {}: {}", sid, e),
+        }
+    }
+
+    let mut notifier = notifier.lock().await;
+    for (id, message) in updates {
+        notifier.update_text(&id, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{self, HttpStub, Reply};
+
+    fn message(sid: &str, status: &str, error_code: Option<u32>) -> serde_json::Value {
+        serde_json::json!({
+            "sid": sid,
+            "status": status,
+            "error_code": error_code,
+            "error_message": error_code.map(|_| "Unreachable destination handset"),
+            "num_segments": "1",
+        })
+    }
+
+    #[tokio::test]
+    async fn sends_a_text() {
+        let twilio = HttpStub::start(|_| Reply::json(201, message("SM1", "queued", None)));
+        let client = Client::new("AC123", "token", &twilio.url);
+
+        let sent = send_twilio_message(
+            "In stock",
+            &client,
+            "+15551112222",
+            "+15553334444",
+            160,
+            &[],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].sid, "SM1");
+        let requests = twilio.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/2010-04-01/Accounts/AC123/Messages.json");
+        assert!(requests[0].headers["authorization"].starts_with("Basic "));
+        assert_eq!(requests[0].form("To"), vec!["+15551112222"]);
+        assert_eq!(requests[0].form("From"), vec!["+15553334444"]);
+        assert_eq!(requests[0].form("Body"), vec!["In stock"]);
+    }
+
+    #[tokio::test]
+    async fn failed_sends_keep_the_error_code() {
+        let twilio = HttpStub::start(|_| {
+            Reply::json(
+                400,
+                serde_json::json!({ "code": 21211, "message": "Invalid 'To' Phone Number" }),
+            )
+        });
+        let client = Client::new("AC123", "token", &twilio.url);
+
+        let error = send_twilio_message("In stock", &client, "+1555", "+15553334444", 160, &[])
+            .await
+            .unwrap_err()
+            .error;
+
+        match &error {
+            NotifyError::TwilioSend(e) => {
+                assert_eq!(e.status, 400);
+                assert_eq!(e.code, Some(21211));
+            }
+            e => panic!("expected a Twilio error, got {}", e),
+        }
+        assert!(TextRecord::failed("+1555", &error).is_permanent_failure());
+    }
+
+    #[tokio::test]
+    async fn undeliverable_numbers_are_turned_off() {
+        let twilio =
+            HttpStub::start(|_| Reply::json(200, message("SM1", "undelivered", Some(30006))));
+        let mut notifier = stub::notifier(
+            serde_json::json!({ "twilio_poll_status": true }),
+            serde_json::json!([{ "service": [], "active": true, "to_phone_number": "+15551112222" }]),
+        );
+        notifier.twilio = Some(Client::new("AC123", "token", &twilio.url));
+        let queued = serde_json::from_value(message("SM1", "queued", None)).unwrap();
+        notifier.record_texts(
+            "+15551112222",
+            vec![TextRecord::sent("+15551112222", TextChannel::Sms, queued)],
+        );
+
+        let notifier = Mutex::new(notifier);
+        poll_text_statuses(&notifier).await;
+
+        let notifier = notifier.lock().await;
+        assert_eq!(
+            twilio.requests()[0].path,
+            "/2010-04-01/Accounts/AC123/Messages/SM1.json"
+        );
+        assert_eq!(
+            notifier.config.subscribers[0].sms_disabled.as_deref(),
+            Some("Unreachable destination handset")
+        );
+        let log = &notifier.config.application_config.sms_log.as_ref().unwrap()["+15551112222"];
+        assert_eq!(log[0].status, "undelivered");
+    }
+
+    #[tokio::test]
+    async fn images_are_capped_and_only_sent_with_the_first_part() {
+        let twilio = HttpStub::start(|_| Reply::json(201, message("SM1", "queued", None)));
+        let client = Client::new("AC123", "token", &twilio.url);
+        let images = (0..MAX_MEDIA + 2)
+            .map(|i| format!("https://example.com/{}.jpg", i))
+            .collect::<Vec<String>>();
+        let images = images.iter().map(String::as_str).collect::<Vec<&str>>();
+
+        send_twilio_message(
+            "In stock at Best Buy\n\nRTX 3080 Founders Edition",
+            &client,
+            "+15551112222",
+            "+15553334444",
+            30,
+            &images,
+        )
         .await
-        .map_err(NotifyError::TwilioSend)?;
+        .unwrap();
+
+        let requests = twilio.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].form("MediaUrl"), &images[..MAX_MEDIA]);
+        assert!(requests[1].form("MediaUrl").is_empty());
+    }
 
-    println!(
-        "Sent [{}] message to {}",
-        message, subscriber.to_phone_number
-    );
+    #[tokio::test]
+    async fn each_channel_has_its_own_address_and_length() {
+        let twilio = HttpStub::start(|_| Reply::json(201, message("SM1", "queued", None)));
+        let mut notifier = stub::notifier(
+            serde_json::json!({
+                "from_phone_number": "+15553334444",
+                "whatsapp_from_number": "+15550000000",
+                "whatsapp_max_length": 30,
+            }),
+            serde_json::json!([
+                { "service": [], "active": true, "to_phone_number": "+15551112222", "text_channel": "whatsapp" },
+                { "service": [], "active": true, "to_phone_number": "+15556667777" },
+            ]),
+        );
+        notifier.twilio = Some(Client::new("AC123", "token", &twilio.url));
+        let message = "In stock at Best Buy\n\nRTX 3080 Founders Edition";
+
+        let subscribers = notifier.config.subscribers.clone();
+        for subscriber in &subscribers {
+            notifier
+                .notify_subscriber(subscriber, message, "", &[], true)
+                .await;
+        }
+
+        let requests = twilio.requests();
+        assert_eq!(requests.len(), 3);
+        for whatsapp in &requests[..2] {
+            assert_eq!(whatsapp.form("To"), vec!["whatsapp:+15551112222"]);
+            assert_eq!(whatsapp.form("From"), vec!["whatsapp:+15550000000"]);
+        }
+        assert_eq!(requests[0].form("Body"), vec!["In stock at Best Buy"]);
+        assert_eq!(requests[2].form("To"), vec!["+15556667777"]);
+        assert_eq!(requests[2].form("From"), vec!["+15553334444"]);
+        assert_eq!(requests[2].form("Body"), vec![message]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_part_keeps_the_parts_sent_before_it() {
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        let twilio = HttpStub::start(move |_| {
+            match calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => Reply::json(201, message("SM1", "queued", None)),
+                _ => Reply::json(500, serde_json::json!({ "code": 20500, "message": "Down" })),
+            }
+        });
+        let client = Client::new("AC123", "token", &twilio.url);
+
+        let failure = send_twilio_message(
+            "In stock at Best Buy\n\nRTX 3080 Founders Edition",
+            &client,
+            "+15551112222",
+            "+15553334444",
+            30,
+            &[],
+        )
+        .await
+        .unwrap_err();
 
-    Ok(())
+        assert_eq!(failure.sent.len(), 1);
+        assert_eq!(failure.sent[0].sid, "SM1");
+        assert!(matches!(failure.error, NotifyError::TwilioSend(_)));
+    }
 }
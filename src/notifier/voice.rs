@@ -0,0 +1,338 @@
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    notifier::{dry_run, is_dry_run, twilio::escape_xml, Alert},
+    subscriber::Subscriber,
+    Notifier,
+};
+
+// How many calls we remember per subscriber
+const MAX_LOGGED_CALLS: usize = 50;
+
+// Calls in these states are over, one way or another
+const FINAL_STATUSES: &[&str] = &["completed", "busy", "no-answer", "failed", "canceled"];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EscalationPolicy {
+    // How long a subscriber has to acknowledge a high priority alert before we call. 0 calls right away
+    pub delay_minutes: u64,
+    // Calls to place before giving up, defaults to 1
+    pub max_attempts: Option<u32>,
+    // Time between unanswered calls, defaults to 5 minutes
+    pub retry_minutes: Option<u64>,
+    // Twilio text to speech voice and language, like "alice" and "en-US"
+    pub voice: Option<String>,
+    pub language: Option<String>,
+}
+
+// A call we owe a subscriber until they acknowledge the alert
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Escalation {
+    pub subscriber_id: String,
+    pub phone: String,
+    // What the call reads out, one line per product
+    pub products: Vec<String>,
+    pub created_at: DateTime<Local>,
+    // When to place the next call
+    pub due_at: DateTime<Local>,
+    pub attempts: u32,
+    // The call currently ringing, if there is one
+    pub call_sid: Option<String>,
+}
+
+// A call we placed or tried to place
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CallRecord {
+    pub to: String,
+    // Missing when Twilio refused the call outright
+    pub sid: Option<String>,
+    pub started_at: DateTime<Local>,
+    pub status: String,
+    // Seconds, once the call has ended
+    pub duration: Option<String>,
+    pub error: Option<String>,
+}
+
+// Read the products out, twice in case the first go was missed
+fn twiml(products: &[String], policy: &EscalationPolicy) -> String {
+    let mut attributes = String::new();
+    if let Some(voice) = &policy.voice {
+        attributes.push_str(&format!(" voice=\"{}\"", escape_xml(voice)));
+    }
+    if let Some(language) = &policy.language {
+        attributes.push_str(&format!(" language=\"{}\"", escape_xml(language)));
+    }
+
+    let speech = format!(
+        "RTX Notifier. {}. Check your messages to buy.",
+        products.join(". ")
+    );
+    format!(
+        "<Response><Say{attrs}>{speech}</Say><Pause length=\"2\"/><Say{attrs}>Again. {speech}</Say></Response>",
+        attrs = attributes,
+        speech = escape_xml(&speech)
+    )
+}
+
+impl Notifier {
+    // Queue a call for anyone sent a high priority alert, if escalation is turned on
+    pub fn queue_escalation(&mut self, subscriber: &Subscriber, alerts: &[&Alert]) {
+        let policy = match &self.config.application_config.escalation {
+            Some(policy) if !is_dry_run() => policy,
+            _ => return,
+        };
+        let phone = match &subscriber.to_phone_number {
+            Some(phone) => phone.clone(),
+            None => return,
+        };
+
+        let products = alerts
+            .iter()
+            .filter(|alert| self.is_high_priority(&alert.product))
+            .map(|alert| match alert.product.get_name() {
+                Ok(name) => format!("{} has {}", alert.product.retailer_name(), name),
+                Err(_) => format!("{} has new products", alert.product.retailer_name()),
+            })
+            .collect::<Vec<String>>();
+        if products.is_empty() {
+            return;
+        }
+
+        let now = Local::now();
+        let due_at = now + Duration::minutes(policy.delay_minutes as i64);
+        let subscriber_id = subscriber.id();
+        let pending = self
+            .config
+            .application_config
+            .pending_escalations
+            .get_or_insert_with(Vec::new);
+        // Anything already waiting just gets the new products added to it
+        match pending
+            .iter_mut()
+            .find(|escalation| escalation.subscriber_id == subscriber_id)
+        {
+            Some(escalation) => {
+                for product in products {
+                    if !escalation.products.contains(&product) {
+                        escalation.products.push(product);
+                    }
+                }
+            }
+            None => {
+                println!(
+                    "Calling {} at {} unless they acknowledge",
+                    subscriber_id,
+                    due_at.format("%H:%M:%S")
+                );
+                pending.push(Escalation {
+                    subscriber_id,
+                    phone,
+                    products,
+                    created_at: now,
+                    due_at,
+                    attempts: 0,
+                    call_sid: None,
+                });
+            }
+        }
+    }
+
+    // The subscriber has seen their alert, so there's no need to call them
+    pub fn acknowledge(&mut self, subscriber_id: &str) {
+        if let Some(pending) = &mut self.config.application_config.pending_escalations {
+            let before = pending.len();
+            pending.retain(|escalation| escalation.subscriber_id != subscriber_id);
+            if pending.len() != before {
+                println!("{} acknowledged, cancelled their call", subscriber_id);
+            }
+        }
+    }
+
+    fn log_call(&mut self, subscriber_id: &str, record: CallRecord) {
+        let log = self
+            .config
+            .application_config
+            .call_log
+            .get_or_insert_with(std::collections::HashMap::new)
+            .entry(subscriber_id.to_string())
+            .or_default();
+        log.push(record);
+        let overflow = log.len().saturating_sub(MAX_LOGGED_CALLS);
+        log.drain(..overflow);
+    }
+
+    fn update_call(&mut self, subscriber_id: &str, call: &crate::notifier::twilio::CallResource) {
+        if let Some(record) = self
+            .config
+            .application_config
+            .call_log
+            .as_mut()
+            .and_then(|log| log.get_mut(subscriber_id))
+            .and_then(|calls| {
+                calls
+                    .iter_mut()
+                    .find(|record| record.sid.as_deref() == Some(call.sid.as_str()))
+            })
+        {
+            if record.status != call.status {
+                println!("Call {} to {} is now {}", call.sid, record.to, call.status);
+            }
+            record.status = call.status.clone();
+            record.duration = call.duration.clone();
+        }
+    }
+}
+
+// Check on calls in progress and place any that are due
+// The notifier isn't locked while we wait on Twilio, so the server can still take ACKs
+pub async fn run_escalations(notifier: &Mutex<Notifier>) {
+    let (client, from_phone, policy, mut pending, sender) = {
+        let notifier = notifier.lock().await;
+        let application_config = &notifier.config.application_config;
+        match (
+            &notifier.twilio,
+            &application_config.from_phone_number,
+            &application_config.escalation,
+            &application_config.pending_escalations,
+        ) {
+            (Some(client), Some(from_phone), Some(policy), Some(pending))
+                if !pending.is_empty() =>
+            {
+                (
+                    client.clone(),
+                    from_phone.clone(),
+                    policy.clone(),
+                    pending.clone(),
+                    notifier.sender(),
+                )
+            }
+            _ => return,
+        }
+    };
+
+    let max_attempts = policy.max_attempts.unwrap_or(1);
+    let retry = Duration::minutes(policy.retry_minutes.unwrap_or(5) as i64);
+    let mut checked = vec![];
+    let mut calls = vec![];
+    let mut answered = vec![];
+    let mut given_up = vec![];
+
+    for escalation in &mut pending {
+        // See how the last call went
+        if let Some(sid) = escalation.call_sid.clone() {
+            let call = match client.fetch_call(&sid).await {
+                Ok(call) => call,
+                Err(e) => {
+                    eprintln!("Couldn't check on call {}: {}", sid, e);
+                    continue;
+                }
+            };
+            checked.push((escalation.subscriber_id.clone(), call.clone()));
+            if !FINAL_STATUSES.contains(&call.status.as_str()) {
+                continue;
+            }
+
+            escalation.call_sid = None;
+            // Voicemail counts too, there's no telling the difference without a callback
+            if call.status == "completed" {
+                answered.push((escalation.subscriber_id.clone(), escalation.created_at));
+                continue;
+            }
+            escalation.due_at = Local::now() + retry;
+        }
+
+        if escalation.attempts >= max_attempts {
+            given_up.push((escalation.subscriber_id.clone(), escalation.created_at));
+            continue;
+        }
+        if escalation.due_at > Local::now() {
+            continue;
+        }
+        let twiml = twiml(&escalation.products, &policy);
+        // Logged without using up an attempt or putting the call off
+        if dry_run(&format!("Call to {}", escalation.phone), &twiml) {
+            continue;
+        }
+        // Held until the budget resets rather than using up an attempt
+        if !sender.reserve_spend(sender.call_cost()) {
+            println!(
+                "Not calling {}, the Twilio budget has been spent",
+                escalation.phone
+            );
+            continue;
+        }
+        escalation.attempts += 1;
+        let record = match client
+            .create_call(&from_phone, &escalation.phone, &twiml)
+            .await
+        {
+            Ok(call) => {
+                println!(
+                    "Calling {} as {}, attempt {} of {}",
+                    escalation.phone, call.sid, escalation.attempts, max_attempts
+                );
+                escalation.call_sid = Some(call.sid.clone());
+                CallRecord {
+                    to: escalation.phone.clone(),
+                    sid: Some(call.sid),
+                    started_at: Local::now(),
+                    status: call.status,
+                    duration: call.duration,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                eprintln!("Couldn't call {}: {}", escalation.phone, e);
+                escalation.due_at = Local::now() + retry;
+                CallRecord {
+                    to: escalation.phone.clone(),
+                    sid: None,
+                    started_at: Local::now(),
+                    status: "failed".to_string(),
+                    duration: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        calls.push((escalation.subscriber_id.clone(), record));
+    }
+
+    let mut notifier = notifier.lock().await;
+    for (subscriber_id, call) in checked {
+        notifier.update_call(&subscriber_id, &call);
+    }
+    for (subscriber_id, record) in calls {
+        if record.sid.is_some() {
+            notifier.record_call_spend(&subscriber_id);
+        }
+        notifier.release_spend(notifier.call_cost());
+        notifier.log_call(&subscriber_id, record);
+    }
+    for (subscriber_id, _) in &given_up {
+        println!("No answer from {}, giving up on calling", subscriber_id);
+    }
+    // Only touch what's still pending, anyone who acknowledged in the meantime stays acknowledged,
+    // and products added in the meantime are kept
+    // Matched on when they were created too, so an escalation queued since is left alone
+    if let Some(current) = &mut notifier.config.application_config.pending_escalations {
+        current.retain(|escalation| {
+            let key = (escalation.subscriber_id.clone(), escalation.created_at);
+            !given_up.contains(&key) && !answered.contains(&key)
+        });
+        for escalation in current.iter_mut() {
+            if let Some(updated) = pending.iter().find(|updated| {
+                updated.subscriber_id == escalation.subscriber_id
+                    && updated.created_at == escalation.created_at
+            }) {
+                escalation.attempts = updated.attempts;
+                escalation.due_at = updated.due_at;
+                escalation.call_sid = updated.call_sid.clone();
+            }
+        }
+    }
+    for (subscriber_id, _) in &answered {
+        println!("{} answered, no more calls", subscriber_id);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    notifier::{dry_run, http_client, Alert},
+    NotifyError,
+};
+
+const DEFAULT_BODY_TEMPLATE: &str = r#"{"provider":"{{provider}}","retailer":"{{retailer}}","product":"{{product}}","url":"{{url}}","price":"{{price}}","timestamp":"{{timestamp}}"}"#;
+const DEFAULT_SIGNATURE_HEADER: &str = "X-Signature-256";
+
+lazy_static! {
+    static ref PLACEHOLDER_REGEX: Regex = Regex::new(r"\{\{(\w+)\}\}").unwrap();
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    // Defaults to POST
+    pub method: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+    // The request body, see `render_body` for the placeholders. Defaults to a JSON object with all of them
+    pub body_template: Option<String>,
+    // When set, the body is signed with HMAC-SHA256 and the signature sent as "sha256=<hex>"
+    pub secret: Option<String>,
+    // Defaults to X-Signature-256
+    pub signature_header: Option<String>,
+}
+
+// Send one request per product, so receivers get one event each
+pub async fn send_webhook(alerts: &[&Alert], webhook: &WebhookConfig) -> Result<(), NotifyError> {
+    let client = http_client();
+    let method = match &webhook.method {
+        Some(method) => reqwest::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+            .map_err(|_| NotifyError::WebhookConfig)?,
+        None => reqwest::Method::POST,
+    };
+
+    for alert in alerts {
+        let body = render_body(
+            webhook
+                .body_template
+                .as_deref()
+                .unwrap_or(DEFAULT_BODY_TEMPLATE),
+            alert,
+        );
+
+        if dry_run(&format!("{} {}", method, webhook.url), &body) {
+            continue;
+        }
+
+        let mut request = client.request(method.clone(), &webhook.url);
+        let headers = webhook.headers.as_ref();
+        let has_content_type = headers
+            .iter()
+            .flat_map(|headers| headers.keys())
+            .any(|name| name.eq_ignore_ascii_case("Content-Type"));
+        if !has_content_type {
+            request = request.header("Content-Type", "application/json");
+        }
+        for (name, value) in headers.into_iter().flatten() {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        if let Some(secret) = &webhook.secret {
+            request = request.header(
+                webhook
+                    .signature_header
+                    .as_deref()
+                    .unwrap_or(DEFAULT_SIGNATURE_HEADER),
+                format!("sha256={}", sign(secret, &body)?),
+            );
+        }
+
+        let res = request
+            .body(body.clone())
+            .send()
+            .await
+            .map_err(NotifyError::WebRequestFailed)?;
+
+        let status = res.status();
+        if !status.is_success() {
+            return Err(NotifyError::WebClient(status));
+        }
+
+        println!("Sent webhook to {}\nPayload: {}", webhook.url, body);
+    }
+
+    Ok(())
+}
+
+// Fill in the {{provider}}, {{retailer}}, {{product}}, {{url}}, {{price}} and {{timestamp}} placeholders
+// Values are escaped so they can sit inside JSON strings. The template is filled in one pass,
+// so a product name with "{{url}}" in it stays as written. Unknown placeholders are left alone
+fn render_body(template: &str, alert: &Alert) -> String {
+    let product = &alert.product;
+    PLACEHOLDER_REGEX
+        .replace_all(template, |captures: &Captures| match &captures[1] {
+            "provider" => escape(product.to_key()),
+            "retailer" => escape(product.retailer_name()),
+            "product" => escape(product.get_name().unwrap_or("")),
+            "url" => escape(product.get_url().unwrap_or("")),
+            "price" => product
+                .get_offer()
+                .and_then(|offer| offer.price())
+                .map(|price| format!("{:.2}", price))
+                .unwrap_or_default(),
+            "timestamp" => Local::now().to_rfc3339(),
+            _ => captures[0].to_string(),
+        })
+        .into_owned()
+}
+
+fn escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+fn sign(secret: &str, body: &str) -> Result<String, NotifyError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|_| NotifyError::WebhookConfig)?;
+    mac.update(body.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub;
+
+    #[test]
+    fn placeholders_in_values_are_not_filled_in() {
+        let alert = stub::alert(stub::product("RTX {{url}} \"3080\""));
+
+        let body = render_body(
+            r#"{"product":"{{product}}","price":"{{price}}","x":"{{x}}"}"#,
+            &alert,
+        );
+
+        assert_eq!(
+            body,
+            r#"{"product":"RTX {{url}} \"3080\"","price":"699.99","x":"{{x}}"}"#
+        );
+    }
+}
@@ -1,7 +1,10 @@
+use std::hash::{Hash, Hasher};
 use std::process::Command;
 
+use chrono::{DateTime, Local};
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
     error::NotifyError,
@@ -11,12 +14,43 @@ use crate::{
     },
 };
 
-#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Default, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ProductDetails {
     pub product: String,
     pub page: String,
     pub active: Option<bool>,
     pub active_chance: Option<u8>,
+    pub catalog_id: Option<String>,
+    pub tags: Option<Vec<String>>,
+    // Filled in by the scraper when it finds the product in stock, never read from the config
+    #[serde(skip)]
+    pub offer: Option<Offer>,
+}
+
+// The same listing is the same product whatever the scraper saw on it, so the offer is left out
+// Otherwise a listing found by two scrapers, or by mail and a scraper, is alerted for twice
+impl PartialEq for ProductDetails {
+    fn eq(&self, other: &Self) -> bool {
+        self.product == other.product
+            && self.page == other.page
+            && self.active == other.active
+            && self.active_chance == other.active_chance
+            && self.catalog_id == other.catalog_id
+            && self.tags == other.tags
+    }
+}
+
+impl Eq for ProductDetails {}
+
+impl Hash for ProductDetails {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.product.hash(state);
+        self.page.hash(state);
+        self.active.hash(state);
+        self.active_chance.hash(state);
+        self.catalog_id.hash(state);
+        self.tags.hash(state);
+    }
 }
 
 impl ProductDetails {
@@ -29,6 +63,80 @@ impl ProductDetails {
     }
 }
 
+// What a scraper saw for a product it found in stock
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Offer {
+    pub price_cents: Option<u64>,
+    pub seller: Option<String>,
+    pub ships_from: Option<ShipsFrom>,
+    // The page's og:image, when it has one
+    pub image_url: Option<String>,
+    // When the scraper saw the offer, set by `with_offer`
+    pub detected_at: Option<DateTime<Local>>,
+    // The part of the page that made the scraper call it in stock
+    pub snapshot: Option<Snapshot>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Snapshot {
+    // What the scraper matched on, or didn't find, to decide it was in stock
+    pub marker: String,
+    // A trimmed piece of the response around where the marker is, or would be
+    pub excerpt: String,
+}
+
+impl Offer {
+    pub fn price(&self) -> Option<f64> {
+        self.price_cents.map(|cents| cents as f64 / 100.0)
+    }
+}
+
+// Who ships the offer, ordered from least to most preferred
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ShipsFrom {
+    // A marketplace seller shipping it themselves
+    ThirdParty,
+    // A marketplace seller, but shipped by the retailer
+    Fulfilled,
+    // Shipped and sold by the retailer
+    Retailer,
+}
+
+// Include and exclude lists of tag expressions. An expression is one or more tags joined by `+`, all of which must be present
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TagFilter {
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+}
+
+impl TagFilter {
+    pub fn matches(&self, tags: &[String]) -> bool {
+        let included = match &self.include {
+            Some(include) if !include.is_empty() => include
+                .iter()
+                .any(|expr| tag_expression_matches(expr, tags)),
+            // Nothing to include means everything is included
+            _ => true,
+        };
+        let excluded = match &self.exclude {
+            Some(exclude) => exclude
+                .iter()
+                .any(|expr| tag_expression_matches(expr, tags)),
+            None => false,
+        };
+
+        included && !excluded
+    }
+}
+
+pub fn tag_expression_matches(expr: &str, tags: &[String]) -> bool {
+    expr.split('+')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .all(|tag| tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+}
+
 #[derive(Eq, PartialEq, Clone, Hash, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Product {
@@ -86,6 +194,65 @@ impl Product {
         Ok(())
     }
 
+    // Get the details of the product, if it has any
+    pub fn get_details(&self) -> Option<&ProductDetails> {
+        match self {
+            Product::Evga(Some(details))
+            | Product::NewEgg(Some(details))
+            | Product::BestBuy(details)
+            | Product::BnH(details)
+            | Product::Amazon(details)
+            | Product::Nvidia(details) => Some(details),
+            _ => None,
+        }
+    }
+
+    // A stable id for the listing, taken from its page since names are often shared between listings
+    pub fn listing_id(&self) -> Option<String> {
+        let hash = Sha256::digest(self.get_url().ok()?.as_bytes());
+        Some(hex::encode(&hash[..6]))
+    }
+
+    // Get the id of the catalog entry this listing is linked to
+    pub fn get_catalog_id(&self) -> Option<&str> {
+        self.get_details()?.catalog_id.as_deref()
+    }
+
+    // Get a mutable reference to the details of the product, if it has any
+    fn get_details_mut(&mut self) -> Option<&mut ProductDetails> {
+        match self {
+            Product::Evga(Some(details))
+            | Product::NewEgg(Some(details))
+            | Product::BestBuy(details)
+            | Product::BnH(details)
+            | Product::Amazon(details)
+            | Product::Nvidia(details) => Some(details),
+            _ => None,
+        }
+    }
+
+    // Get the offer the scraper saw, if it reported one
+    pub fn get_offer(&self) -> Option<&Offer> {
+        self.get_details()?.offer.as_ref()
+    }
+
+    // Clone the product with the offer a scraper saw attached
+    pub fn with_offer(&self, mut offer: Offer) -> Product {
+        offer.detected_at.get_or_insert_with(Local::now);
+        let mut product = self.clone();
+        if let Some(details) = product.get_details_mut() {
+            details.offer = Some(offer);
+        }
+        product
+    }
+
+    // Get the free-form tags of the product
+    pub fn get_tags(&self) -> &[String] {
+        self.get_details()
+            .and_then(|details| details.tags.as_deref())
+            .unwrap_or(&[])
+    }
+
     // Get the page from the Product
     pub fn get_url(&self) -> Result<&str, NotifyError> {
         // Get a reference to the page property of each product.rs type
@@ -184,6 +351,10 @@ impl Product {
         }
     }
 
+    // Every key `to_key` can return
+    pub const KEYS: &'static [&'static str] =
+        &["evga", "newegg", "nvidia", "bestbuy", "bnh", "amazon"];
+
     // Get the product.rs key from the type
     pub fn to_key(&self) -> &'static str {
         use Product::*;
@@ -197,6 +368,19 @@ impl Product {
         }
     }
 
+    // Get the retailer's name for display purposes
+    pub fn retailer_name(&self) -> &'static str {
+        use Product::*;
+        match self {
+            Evga(_) => "EVGA",
+            NewEgg(_) => "NewEgg",
+            Nvidia(_) => "Nvidia",
+            BestBuy(_) => "Bestbuy",
+            BnH(_) => "BnH",
+            Amazon(_) => "Amazon",
+        }
+    }
+
     // Get the product.rs info from the key, name, and url
     pub fn from_product(key: &str, product: String, page: String) -> Option<Self> {
         match key {
@@ -224,6 +408,19 @@ impl Product {
         }
     }
 
+    // Get the product for a provider key. Only evga and newegg work without details, as a generic alert
+    pub fn from_key(key: &str, details: Option<ProductDetails>) -> Option<Self> {
+        match (key, details) {
+            ("evga", details) => Some(Product::Evga(details)),
+            ("newegg", details) => Some(Product::NewEgg(details)),
+            ("bestbuy", Some(details)) => Some(Product::BestBuy(details)),
+            ("nvidia", Some(details)) => Some(Product::Nvidia(details)),
+            ("bnh", Some(details)) => Some(Product::BnH(details)),
+            ("amazon", Some(details)) => Some(Product::Amazon(details)),
+            _ => None,
+        }
+    }
+
     // Get some new in stock messages depending on product.rs type
     pub fn new_stock_message(&self) -> String {
         match self {
@@ -250,3 +447,35 @@ impl Product {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn offers_dont_make_a_different_product() {
+        let details = ProductDetails::new_from_product_and_page(
+            "RTX 3080".to_string(),
+            "https://www.bestbuy.com/site/3080".to_string(),
+        );
+        let seen = Product::BestBuy(details.clone()).with_offer(Offer {
+            price_cents: Some(69_999),
+            ..Offer::default()
+        });
+        let seen_again = Product::BestBuy(details).with_offer(Offer {
+            price_cents: Some(74_999),
+            ..Offer::default()
+        });
+
+        assert_eq!(seen, seen_again);
+        assert_eq!(
+            vec![seen, seen_again]
+                .into_iter()
+                .collect::<HashSet<Product>>()
+                .len(),
+            1
+        );
+    }
+}
@@ -6,12 +6,25 @@ use regex::{Regex, RegexBuilder};
 use reqwest::header::HeaderMap;
 use tokio::io::AsyncWriteExt;
 
-use crate::{error::NotifyError, product::Product, scraping::ScrapingProvider};
+use crate::{
+    error::NotifyError,
+    product::{Offer, Product, ShipsFrom},
+    scraping::{parse_og_image, parse_price, snapshot, ScrapingProvider},
+};
 
 lazy_static! {
     // See if it's offering us a sale on another seller
     static ref OTHER_SELLER_REGEX: Regex =
         RegexBuilder::new("Available from .+these sellers</a>").case_insensitive(true).build().unwrap();
+    // The buy box price
+    static ref PRICE_REGEX: Regex =
+        Regex::new(r#"id="(?:priceblock_ourprice|priceblock_saleprice|priceblock_dealprice)"[^>]*>\s*(\$[\d,]+\.\d{2})"#).unwrap();
+    // The "Ships from and sold by" blurb under the buy box
+    static ref MERCHANT_REGEX: Regex =
+        Regex::new(r#"(?s)id="merchant-info"[^>]*>(.*?)</div>"#).unwrap();
+    static ref TAG_REGEX: Regex = Regex::new(r#"<[^>]*>"#).unwrap();
+    static ref SOLD_BY_REGEX: Regex =
+        RegexBuilder::new(r#"sold by (.+?)(?: and fulfilled by amazon)?\.?$"#).case_insensitive(true).build().unwrap();
 }
 
 static CAPTCHA_TEXT: &str = r#"<p class="a-last">Sorry, we just need to make sure you're not a robot. For best results, please make sure your browser is accepting cookies.</p>"#;
@@ -38,13 +51,59 @@ impl<'a> ScrapingProvider<'a> for AmazonScraper {
         if !resp_text.contains(r#"Currently unavailable.</span>"#)
             && !OTHER_SELLER_REGEX.is_match(&resp_text)
         {
-            return Ok(product.clone());
+            return Ok(product.with_offer(parse_offer(&resp_text)));
         }
 
         Err(NotifyError::NoProductFound)
     }
 }
 
+fn parse_offer(resp_text: &str) -> Offer {
+    let price_cents = PRICE_REGEX
+        .captures(resp_text)
+        .and_then(|capture| parse_price(&capture[1]));
+
+    let merchant = MERCHANT_REGEX.captures(resp_text).map(|capture| {
+        // Strip the links out of the blurb and collapse the whitespace
+        TAG_REGEX
+            .replace_all(&capture[1], "")
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+    });
+
+    let (seller, ships_from) = match merchant {
+        Some(merchant) => {
+            let lower = merchant.to_ascii_lowercase();
+            let seller = SOLD_BY_REGEX
+                .captures(&merchant)
+                .map(|capture| capture[1].trim().to_string());
+            let ships_from = if lower.contains("sold by amazon.com") {
+                ShipsFrom::Retailer
+            } else if lower.contains("fulfilled by amazon") {
+                ShipsFrom::Fulfilled
+            } else {
+                ShipsFrom::ThirdParty
+            };
+            (seller, Some(ships_from))
+        }
+        None => (None, None),
+    };
+
+    Offer {
+        price_cents,
+        seller,
+        ships_from,
+        image_url: parse_og_image(resp_text),
+        snapshot: Some(snapshot(
+            resp_text,
+            "No \"Currently unavailable\" or other sellers notice",
+            resp_text.find(r#"id="add-to-cart-button""#),
+        )),
+        ..Offer::default()
+    }
+}
+
 #[allow(dead_code)]
 async fn write_amazon_response<'a, T: Into<&'a [u8]>>(
     resp: T,
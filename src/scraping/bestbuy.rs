@@ -3,7 +3,11 @@ use lazy_static::lazy_static;
 use regex::{Regex, RegexBuilder};
 use reqwest::header::HeaderMap;
 
-use crate::{error::NotifyError, product::Product, scraping::ScrapingProvider};
+use crate::{
+    error::NotifyError,
+    product::{Offer, Product, ShipsFrom},
+    scraping::{parse_og_image, parse_price, snapshot, ScrapingProvider},
+};
 
 // Look for the div that says it's Sold Out, case insensitive. Give it a bit of before and after HTML so that it doesn't false match on other elements
 lazy_static! {
@@ -12,6 +16,10 @@ lazy_static! {
             .case_insensitive(true)
             .build()
             .expect("Invalid regex");
+    static ref PRICE_REGEX: Regex = Regex::new(
+        r#"priceView-customer-price"><span aria-hidden="true">(\$[\d,]+\.\d{2})</span>"#
+    )
+    .expect("Invalid regex");
 }
 
 pub struct BestBuyScraper;
@@ -49,7 +57,21 @@ impl<'a> ScrapingProvider<'a> for BestBuyScraper {
 
         // If we can't find the sold out button, we're back in stock
         if BUTTON_REGEX.captures_iter(&resp).next().is_none() {
-            return Ok(product.clone());
+            // Best Buy doesn't have a marketplace, everything is sold and shipped by them
+            return Ok(product.with_offer(Offer {
+                price_cents: PRICE_REGEX
+                    .captures(&resp)
+                    .and_then(|capture| parse_price(&capture[1])),
+                seller: Some("Best Buy".to_string()),
+                ships_from: Some(ShipsFrom::Retailer),
+                image_url: parse_og_image(&resp),
+                snapshot: Some(snapshot(
+                    &resp,
+                    "No Sold Out button",
+                    PRICE_REGEX.find(&resp).map(|price| price.start()),
+                )),
+                ..Offer::default()
+            }));
         }
 
         Err(NotifyError::NoProductFound)
@@ -1,6 +1,17 @@
 use async_trait::async_trait;
+use lazy_static::lazy_static;
+use regex::Regex;
 
-use crate::{error::NotifyError, product::Product, scraping::ScrapingProvider};
+use crate::{
+    error::NotifyError,
+    product::{Offer, Product, ShipsFrom},
+    scraping::{parse_og_image, parse_price, snapshot, ScrapingProvider},
+};
+
+lazy_static! {
+    // The price in the structured product data
+    static ref PRICE_REGEX: Regex = Regex::new(r#""price":\s*"?([\d.]+)"#).unwrap();
+}
 
 pub struct BnHScraper;
 
@@ -19,7 +30,20 @@ impl<'a> ScrapingProvider<'a> for BnHScraper {
         if resp.contains(r#"showNotifyWhenAvailable": false"#)
             && resp.contains(r#"showNotifyWhenInStock": false"#)
         {
-            return Ok(product.clone());
+            return Ok(product.with_offer(Offer {
+                price_cents: PRICE_REGEX
+                    .captures(&resp)
+                    .and_then(|capture| parse_price(&capture[1])),
+                seller: Some("B&H".to_string()),
+                ships_from: Some(ShipsFrom::Retailer),
+                image_url: parse_og_image(&resp),
+                snapshot: Some(snapshot(
+                    &resp,
+                    r#"showNotifyWhenAvailable": false"#,
+                    resp.find(r#"showNotifyWhenAvailable": false"#),
+                )),
+                ..Offer::default()
+            }));
         }
 
         Err(NotifyError::NoProductFound)
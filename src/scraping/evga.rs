@@ -2,7 +2,11 @@ use async_trait::async_trait;
 use scraper::{Html, Selector};
 use std::io::Write;
 
-use crate::{error::NotifyError, product::Product, scraping::ScrapingProvider};
+use crate::{
+    error::NotifyError,
+    product::{Offer, Product, ShipsFrom},
+    scraping::{parse_og_image, snapshot, ScrapingProvider},
+};
 
 pub struct EvgaScraper;
 
@@ -37,7 +41,18 @@ impl<'a> ScrapingProvider<'a> for EvgaScraper {
                     .contains("out of stock"))
         {
             let _ = write_evga_response(resp.as_bytes());
-            return Ok(product.clone());
+            return Ok(product.with_offer(Offer {
+                price_cents: None,
+                seller: Some("EVGA".to_string()),
+                ships_from: Some(ShipsFrom::Retailer),
+                image_url: parse_og_image(&resp),
+                snapshot: Some(snapshot(
+                    &resp,
+                    "No out of stock text in #LFrame_pnlOutOfStock",
+                    resp.find("LFrame_pnlOutOfStock"),
+                )),
+                ..Offer::default()
+            }));
         }
 
         Err(NotifyError::NoProductFound)
@@ -1,9 +1,12 @@
 use std::collections::{HashMap, HashSet};
 
 use async_trait::async_trait;
+use lazy_static::lazy_static;
+use regex::Regex;
+use tokio::sync::Mutex;
 
 use crate::error::NotifyError;
-use crate::product::Product;
+use crate::product::{Product, Snapshot};
 use crate::Notifier;
 
 pub mod amazon;
@@ -13,6 +16,17 @@ pub mod evga;
 pub mod newegg;
 pub mod nvidia;
 
+// How much of the page to keep either side of a snapshot's marker
+const SNAPSHOT_RADIUS: usize = 2000;
+
+lazy_static! {
+    // The Open Graph image, with its attributes in either order
+    static ref OG_IMAGE_REGEX: Regex = Regex::new(
+        r#"<meta[^>]+(?:property="og:image"[^>]+content="([^"]+)"|content="([^"]+)"[^>]+property="og:image")"#
+    )
+    .unwrap();
+}
+
 #[async_trait]
 pub trait ScrapingProvider<'a> {
     async fn get_request(
@@ -85,32 +99,50 @@ fn get_client(notifier: &Notifier) -> Result<reqwest::Client, NotifyError> {
     Ok(client)
 }
 
+// What a scraping pass found, split by whether each product had stock
+pub struct ScrapeResults {
+    pub in_stock: HashSet<Product>,
+    // Checked and confirmed out of stock, products that errored aren't in either set
+    pub out_of_stock: Vec<Product>,
+}
+
 pub async fn get_providers_from_scraping(
-    notifier: &mut Notifier,
-) -> Result<HashSet<Product>, NotifyError> {
-    let client = get_client(&notifier)?;
-    let active_products = notifier
-        .config
-        .products
-        .iter()
-        .filter(|p| p.is_active() && notifier.config.application_config.should_scrape(p.to_key()))
-        .cloned()
-        .collect::<Vec<Product>>();
+    notifier: &Mutex<Notifier>,
+) -> Result<ScrapeResults, NotifyError> {
+    let (client, active_products) = {
+        let notifier = notifier.lock().await;
+        let active_products = notifier
+            .config
+            .products
+            .iter()
+            .filter(|p| {
+                p.is_active() && notifier.config.application_config.should_scrape(p.to_key())
+            })
+            .cloned()
+            .collect::<Vec<Product>>();
+        (get_client(&notifier)?, active_products)
+    };
 
     let mut futs = vec![];
     for product in &active_products {
         futs.push(product.is_available(&client));
     }
 
+    // Nothing's locked while the sites are checked, so texts and mail can be handled in the meantime
     let joined = futures::future::join_all(futs).await;
+    let mut notifier = notifier.lock().await;
 
     let mut checked: HashMap<&str, (usize, Vec<String>)> = HashMap::new();
+    // Checked and found listing counts, per catalog card
+    let mut cards: HashMap<&str, (usize, usize)> = HashMap::new();
     let mut providers = HashSet::new();
+    let mut out_of_stock = vec![];
     for (i, res) in joined.into_iter().enumerate() {
         let product = &active_products[i];
         match res {
             Ok(res) => {
                 modify_checked_map(product, &mut checked);
+                modify_card_map(product, true, &mut cards);
                 if !providers.insert(res) {
                     eprintln!("Duplicate provider found.");
                 }
@@ -120,7 +152,11 @@ pub async fn get_providers_from_scraping(
                 notifier.add_ratelimit(&product);
             }
             Err(NotifyError::WebRequestFailed(e)) => print_err(product, e),
-            Err(NotifyError::NoProductFound) => modify_checked_map(product, &mut checked),
+            Err(NotifyError::NoProductFound) => {
+                modify_checked_map(product, &mut checked);
+                modify_card_map(product, false, &mut cards);
+                out_of_stock.push(product.clone());
+            }
             Err(e) => print_err(product, e),
         }
     }
@@ -130,7 +166,64 @@ pub async fn get_providers_from_scraping(
         println!("[{:02}] {}: {:?}", count, key, list);
     }
 
-    Ok(providers)
+    if !cards.is_empty() {
+        println!("Cards Checked:");
+        for (id, (count, found)) in &cards {
+            let name = notifier
+                .config
+                .catalog
+                .iter()
+                .flatten()
+                .find(|entry| &entry.id == id)
+                .map(|entry| entry.display_name())
+                .unwrap_or_else(|| id.to_string());
+            println!("[{:02}] {}: {} in stock", count, name, found);
+        }
+    }
+
+    Ok(ScrapeResults {
+        in_stock: providers,
+        out_of_stock,
+    })
+}
+
+// Parse a price like "$1,499.99" into cents
+pub fn parse_price(text: &str) -> Option<u64> {
+    let cleaned = text.trim().trim_start_matches('$').replace(",", "");
+    let price = cleaned.parse::<f64>().ok()?;
+    if price.is_sign_negative() {
+        return None;
+    }
+
+    Some((price * 100.0).round() as u64)
+}
+
+// Find the product image retailers advertise for link previews
+pub fn parse_og_image(html: &str) -> Option<String> {
+    let capture = OG_IMAGE_REGEX.captures(html)?;
+    capture
+        .get(1)
+        .or_else(|| capture.get(2))
+        .map(|image| image.as_str().replace("&amp;", "&"))
+}
+
+// Keep the part of the page that made us call it in stock, so an alert can be checked after the fact
+// Verdicts based on something missing from the page have no position, so the excerpt starts at the page body
+pub fn snapshot(body: &str, marker: &str, position: Option<usize>) -> Snapshot {
+    let center = position.or_else(|| body.find("<body")).unwrap_or(0);
+    let mut start = center.saturating_sub(SNAPSHOT_RADIUS);
+    let mut end = center.saturating_add(SNAPSHOT_RADIUS).min(body.len());
+    while !body.is_char_boundary(start) {
+        start -= 1;
+    }
+    while !body.is_char_boundary(end) {
+        end += 1;
+    }
+
+    Snapshot {
+        marker: marker.to_string(),
+        excerpt: body[start..end].trim().to_string(),
+    }
 }
 
 fn print_err(product: &Product, e: impl std::error::Error) {
@@ -149,3 +242,17 @@ fn modify_checked_map(product: &Product, map: &mut HashMap<&str, (usize, Vec<Str
         })
         .or_insert((1, vec![name]));
 }
+
+fn modify_card_map<'a>(
+    product: &'a Product,
+    found: bool,
+    map: &mut HashMap<&'a str, (usize, usize)>,
+) {
+    if let Some(id) = product.get_catalog_id() {
+        let entry = map.entry(id).or_insert((0, 0));
+        entry.0 += 1;
+        if found {
+            entry.1 += 1;
+        }
+    }
+}
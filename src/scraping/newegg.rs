@@ -2,12 +2,18 @@ use async_trait::async_trait;
 use lazy_static::lazy_static;
 use regex::Regex;
 
-use crate::{error::NotifyError, product::Product, scraping::ScrapingProvider};
+use crate::{
+    error::NotifyError,
+    product::{Offer, Product, ShipsFrom},
+    scraping::{parse_og_image, parse_price, snapshot, ScrapingProvider},
+};
 
 lazy_static! {
     // Look for the javascript tag that loads the raw product.rs data from their webservers
     static ref DETAIL_REGEX: Regex =
         Regex::new(r#"<script type="text/javascript" src="(.+ItemInfo4.+)">"#).unwrap();
+    static ref PRICE_REGEX: Regex = Regex::new(r#""FinalPrice":\s*"?([\d.]+)"#).unwrap();
+    static ref SELLER_REGEX: Regex = Regex::new(r#""SellerName":\s*"([^"]+)""#).unwrap();
 }
 
 pub struct NeweggScraper;
@@ -44,10 +50,40 @@ impl<'a> ScrapingProvider<'a> for NeweggScraper {
 
             // Then look for the JSON property that shows it's in stock. Yes, we could serialize this but why bother right now
             if product_resp.contains(r#""instock":true"#) {
-                return Ok(product.clone());
+                return Ok(product.with_offer(Offer {
+                    // The image is on the product page, not in the item details
+                    image_url: parse_og_image(&resp),
+                    // The verdict comes from the item details, so that's what we keep
+                    snapshot: Some(snapshot(
+                        &product_resp,
+                        r#""instock":true"#,
+                        product_resp.find(r#""instock":true"#),
+                    )),
+                    ..parse_offer(&product_resp)
+                }));
             }
         }
 
         Err(NotifyError::NoProductFound)
     }
 }
+
+fn parse_offer(product_resp: &str) -> Offer {
+    let seller = SELLER_REGEX
+        .captures(product_resp)
+        .map(|capture| capture[1].to_string());
+    // Marketplace listings ship from whoever is selling them
+    let ships_from = match &seller {
+        Some(seller) if !seller.eq_ignore_ascii_case("newegg") => ShipsFrom::ThirdParty,
+        _ => ShipsFrom::Retailer,
+    };
+
+    Offer {
+        price_cents: PRICE_REGEX
+            .captures(product_resp)
+            .and_then(|capture| parse_price(&capture[1])),
+        seller: Some(seller.unwrap_or_else(|| "Newegg".to_string())),
+        ships_from: Some(ships_from),
+        ..Offer::default()
+    }
+}
@@ -1,6 +1,10 @@
 use async_trait::async_trait;
 
-use crate::{error::NotifyError, product::Product, scraping::ScrapingProvider};
+use crate::{
+    error::NotifyError,
+    product::{Offer, Product, ShipsFrom},
+    scraping::{parse_og_image, snapshot, ScrapingProvider},
+};
 
 static OUT_OF_STOCK_HTML: &str = r#"<div class="cta-button btn show-out-of-stock stock-grey-out" data-nvnotify-form-path="null" data-theme-override="null">Out Of Stock</div>"#;
 
@@ -25,6 +29,12 @@ impl<'a> ScrapingProvider<'a> for NvidiaScraper {
             return Err(NotifyError::NoProductFound);
         }
 
-        Ok(product.clone())
+        Ok(product.with_offer(Offer {
+            seller: Some("Nvidia".to_string()),
+            ships_from: Some(ShipsFrom::Retailer),
+            image_url: parse_og_image(&text),
+            snapshot: Some(snapshot(&text, "No Out Of Stock button", None)),
+            ..Offer::default()
+        }))
     }
 }
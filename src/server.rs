@@ -0,0 +1,293 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use tokio::sync::Mutex;
+
+use crate::{
+    claim::ClaimScope, config::write_config, notifier::twilio::escape_xml, Notifier, NotifyError,
+};
+
+// Where Twilio posts incoming texts
+const SMS_PATH: &str = "/sms";
+// Claim links are this followed by the link's token
+const CLAIM_PATH: &str = "/claim/";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerConfig {
+    // Address to listen on, like "0.0.0.0:8080"
+    pub listen: String,
+    // The address Twilio reaches us at, like "https://notifier.example.com". Twilio signs requests against it
+    pub public_url: String,
+}
+
+// Take requests until the process exits
+pub async fn serve(
+    notifier: Arc<Mutex<Notifier>>,
+    config: ServerConfig,
+) -> Result<(), NotifyError> {
+    let addr = config
+        .listen
+        .parse::<SocketAddr>()
+        .map_err(|_| NotifyError::ServerConfig)?;
+    let config = Arc::new(config);
+
+    let make_service = make_service_fn(move |_| {
+        let notifier = notifier.clone();
+        let config = config.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, notifier.clone(), config.clone())
+            }))
+        }
+    });
+
+    let server = hyper::Server::try_bind(&addr).map_err(NotifyError::Server)?;
+    println!("Listening for texts on {}{}", addr, SMS_PATH);
+    server
+        .serve(make_service)
+        .await
+        .map_err(NotifyError::Server)
+}
+
+async fn handle(
+    req: Request<Body>,
+    notifier: Arc<Mutex<Notifier>>,
+    config: Arc<ServerConfig>,
+) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_string();
+    let response = match (req.method(), path.as_str()) {
+        (&Method::POST, SMS_PATH) => receive_sms(req, &notifier, &config).await,
+        (&Method::GET, path) if path.starts_with(CLAIM_PATH) => {
+            claim_page(&path[CLAIM_PATH.len()..], &notifier).await
+        }
+        (&Method::POST, path) if path.starts_with(CLAIM_PATH) => {
+            let token = path[CLAIM_PATH.len()..].to_string();
+            claim(req, &token, &notifier).await
+        }
+        _ => empty(StatusCode::NOT_FOUND),
+    };
+
+    Ok(response)
+}
+
+async fn receive_sms(
+    req: Request<Body>,
+    notifier: &Mutex<Notifier>,
+    config: &ServerConfig,
+) -> Response<Body> {
+    let url = format!(
+        "{}{}",
+        config.public_url.trim_end_matches('/'),
+        req.uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or(SMS_PATH)
+    );
+    let signature = req
+        .headers()
+        .get("X-Twilio-Signature")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return empty(StatusCode::BAD_REQUEST),
+    };
+    let params = form_urlencoded::parse(&body)
+        .into_owned()
+        .collect::<Vec<(String, String)>>();
+
+    // The loop only holds the lock briefly, never while it's waiting on the network
+    let mut notifier = notifier.lock().await;
+    let verified = match (
+        &notifier.config.application_config.twilio_auth_token,
+        signature,
+    ) {
+        (Some(auth_token), Some(signature)) => {
+            signature_matches(auth_token, &url, &params, &signature)
+        }
+        (None, _) => {
+            eprintln!("Can't check texts came from Twilio without twilio_auth_token");
+            false
+        }
+        (_, None) => false,
+    };
+    if !verified {
+        eprintln!("Rejected a text to {} that wasn't signed by Twilio", url);
+        return empty(StatusCode::FORBIDDEN);
+    }
+
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let (from, text) = match (param("From"), param("Body")) {
+        (Some(from), Some(text)) => (from, text),
+        _ => return empty(StatusCode::BAD_REQUEST),
+    };
+
+    let reply = notifier.run_sms_command(from, text);
+    if let Err(e) = write_config(&mut notifier).await {
+        eprintln!("Couldn't save the command from {}: {}", from, e);
+    }
+
+    Response::builder()
+        .header("Content-Type", "text/xml")
+        .body(Body::from(format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response><Message>{}</Message></Response>",
+            escape_xml(&reply)
+        )))
+        .unwrap_or_else(|_| empty(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Opening a link only asks, so link previews in messaging apps can't claim anything
+async fn claim_page(token: &str, notifier: &Mutex<Notifier>) -> Response<Body> {
+    let notifier = notifier.lock().await;
+    let link = match notifier.find_claim_link(token) {
+        Some(link) => link,
+        None => return html(StatusCode::NOT_FOUND, "This link has expired."),
+    };
+
+    html(
+        StatusCode::OK,
+        &format!(
+            "<p>Did you get {}?</p>\
+            <form method=\"post\"><button name=\"scope\" value=\"subscriber\">Yes, stop my alerts for it</button></form>\
+            <form method=\"post\"><button name=\"scope\" value=\"everyone\">Yes, stop everyone's alerts for it</button></form>",
+            escape_xml(&link.product)
+        ),
+    )
+}
+
+async fn claim(req: Request<Body>, token: &str, notifier: &Mutex<Notifier>) -> Response<Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return empty(StatusCode::BAD_REQUEST),
+    };
+    let scope = form_urlencoded::parse(&body)
+        .find(|(name, _)| name == "scope")
+        .map(|(_, value)| value.into_owned());
+    let scope = match scope.as_deref() {
+        Some("subscriber") => ClaimScope::Subscriber,
+        Some("everyone") => ClaimScope::Everyone,
+        _ => return empty(StatusCode::BAD_REQUEST),
+    };
+
+    let mut notifier = notifier.lock().await;
+    let link = match notifier.find_claim_link(token) {
+        Some(link) => link.clone(),
+        None => return html(StatusCode::NOT_FOUND, "This link has expired."),
+    };
+    let claim = notifier.claim_linked(&link, scope);
+    if let Err(e) = write_config(&mut notifier).await {
+        eprintln!("Couldn't save the claim from {}: {}", link.subscriber_id, e);
+    }
+
+    html(
+        StatusCode::OK,
+        &format!(
+            "<p>Congrats! {} is claimed, alerts for it are off for {}.</p>",
+            escape_xml(&claim.product),
+            match scope {
+                ClaimScope::Subscriber => "you",
+                ClaimScope::Everyone => "everyone",
+            }
+        ),
+    )
+}
+
+// Twilio signs the full URL followed by each form parameter's name and value, sorted by name
+// https://www.twilio.com/docs/usage/security#validating-requests
+fn signature_matches(
+    auth_token: &str,
+    url: &str,
+    params: &[(String, String)],
+    signature: &str,
+) -> bool {
+    let expected = match base64::decode(signature) {
+        Ok(expected) => expected,
+        Err(_) => return false,
+    };
+    let mut mac = match Hmac::<Sha1>::new_from_slice(auth_token.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+
+    let mut params = params.iter().collect::<Vec<&(String, String)>>();
+    params.sort();
+    mac.update(url.as_bytes());
+    for (name, value) in params {
+        mac.update(name.as_bytes());
+        mac.update(value.as_bytes());
+    }
+
+    mac.verify_slice(&expected).is_ok()
+}
+
+fn html(status: StatusCode, content: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(format!(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\"><title>RTX Notifier</title></head><body>{}</body></html>",
+        content
+    )));
+    *response.status_mut() = status;
+    response
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Vec<(String, String)> {
+        [
+            ("CallSid", "CA1234567890ABCDE"),
+            ("Caller", "+12349013030"),
+            ("Digits", "1234"),
+            ("From", "+12349013030"),
+            ("To", "+18005551212"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    // The example from Twilio's docs on validating requests
+    #[test]
+    fn twilio_signatures_are_checked() {
+        let url = "https://mycompany.com/myapp.php?foo=1&bar=2";
+
+        assert!(signature_matches(
+            "12345",
+            url,
+            &params(),
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        ));
+        let mut tampered = params();
+        tampered[2].1 = "4321".to_string();
+        assert!(!signature_matches(
+            "12345",
+            url,
+            &tampered,
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        ));
+        assert!(!signature_matches(
+            "54321",
+            url,
+            &params(),
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        ));
+        assert!(!signature_matches("12345", url, &params(), "not base64!"));
+    }
+}
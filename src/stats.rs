@@ -0,0 +1,71 @@
+use chrono::Local;
+
+use crate::{notifier::budget::Usage, Notifier};
+
+impl Notifier {
+    // A report of what's been spent and claimed, for `evga-notifier stats`
+    pub fn stats(&self) -> String {
+        let application_config = &self.config.application_config;
+        let budget = application_config.budget.as_ref();
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let month = Local::now().format("%Y-%m").to_string();
+
+        let mut lines = vec![];
+        lines.push(spend_line(
+            "Today",
+            &self.spend_since(&today),
+            budget.and_then(|budget| budget.daily_limit),
+        ));
+        lines.push(spend_line(
+            "This month",
+            &self.spend_since(&month),
+            budget.and_then(|budget| budget.monthly_limit),
+        ));
+        if budget.is_none() {
+            lines.push("No budget configured, costs are all $0.00".to_string());
+        }
+
+        let mut subscribers = std::collections::BTreeMap::<&str, Usage>::new();
+        for (subscriber_id, usage) in application_config
+            .spend_log
+            .iter()
+            .flatten()
+            .filter(|(day, _)| day.starts_with(&month))
+            .flat_map(|(_, subscribers)| subscribers)
+        {
+            subscribers.entry(subscriber_id).or_default().add(usage);
+        }
+        if !subscribers.is_empty() {
+            lines.push(String::new());
+            lines.push("This month by subscriber:".to_string());
+            for (subscriber_id, usage) in subscribers {
+                lines.push(format!("  {}: {}", subscriber_id, describe(&usage)));
+            }
+        }
+
+        if let Some(claims) = application_config.claims.as_ref().filter(|c| !c.is_empty()) {
+            lines.push(String::new());
+            lines.push("Claims:".to_string());
+            for claim in claims {
+                lines.push(format!("  {}", claim.describe()));
+            }
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
+
+fn spend_line(period: &str, usage: &Usage, limit: Option<f64>) -> String {
+    match limit {
+        Some(limit) => format!("{}: {} of ${:.2}", period, describe(usage), limit),
+        None => format!("{}: {}", period, describe(usage)),
+    }
+}
+
+fn describe(usage: &Usage) -> String {
+    format!(
+        "${:.2} ({} SMS segments, {} MMS, {} WhatsApp, {} calls)",
+        usage.cost, usage.sms_segments, usage.mms, usage.whatsapp, usage.calls
+    )
+}
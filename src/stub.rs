@@ -0,0 +1,336 @@
+// Local stand-ins for the services we talk to, for tests
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::Local;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::Config;
+use crate::notifier::Alert;
+use crate::product::{Offer, Product, ProductDetails};
+use crate::Notifier;
+
+// A request the stub was sent
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Request {
+    // Every value of a form field, in order
+    pub fn form(&self, key: &str) -> Vec<String> {
+        form_urlencoded::parse(self.body.as_bytes())
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+            .collect()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+// What the stub answers with
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl Reply {
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        Reply {
+            status,
+            headers: vec![("Content-Type", "application/json".to_string())],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn ok() -> Self {
+        Reply {
+            status: 200,
+            headers: vec![],
+            body: "ok".to_string(),
+        }
+    }
+}
+
+// An HTTP server on a free local port, answering every request with `respond`
+pub struct HttpStub {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl HttpStub {
+    pub fn start<F>(respond: F) -> HttpStub
+    where
+        F: Fn(&Request) -> Reply + Send + Sync + 'static,
+    {
+        let respond = Arc::new(respond);
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+
+        let make_service = make_service_fn(move |_| {
+            let respond = respond.clone();
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let respond = respond.clone();
+                    let recorded = recorded.clone();
+                    async move {
+                        let method = req.method().to_string();
+                        let path = req.uri().to_string();
+                        let headers = req
+                            .headers()
+                            .iter()
+                            .map(|(k, v)| {
+                                (k.to_string(), v.to_str().unwrap_or_default().to_string())
+                            })
+                            .collect();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let request = Request {
+                            method,
+                            path,
+                            headers,
+                            body: String::from_utf8_lossy(&body).into_owned(),
+                        };
+
+                        let reply = respond(&request);
+                        recorded.lock().unwrap().push(request);
+                        let mut response = Response::builder().status(reply.status);
+                        for (name, value) in reply.headers {
+                            response = response.header(name, value);
+                        }
+                        Ok::<_, Infallible>(response.body(Body::from(reply.body)).unwrap())
+                    }
+                }))
+            }
+        });
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = hyper::Server::bind(&addr).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        HttpStub { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// An SMTP server on a free local port that keeps every message it's sent
+pub struct SmtpSink {
+    pub port: u16,
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl SmtpSink {
+    pub async fn start() -> SmtpSink {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let messages = Arc::new(Mutex::new(vec![]));
+        let received = messages.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = tokio::io::split(stream);
+                    let mut lines = BufReader::new(reader).lines();
+                    writer.write_all(b"220 localhost\r\n").await.unwrap();
+                    let mut data: Option<Vec<String>> = None;
+                    while let Some(Ok(line)) = lines.next_line().await.transpose() {
+                        if let Some(message) = data.as_mut() {
+                            if line == "." {
+                                received.lock().unwrap().push(message.join("\r\n"));
+                                data = None;
+                                writer.write_all(b"250 queued\r\n").await.unwrap();
+                            } else {
+                                message.push(line);
+                            }
+                            continue;
+                        }
+
+                        let reply: &[u8] = match line.get(..4).unwrap_or("").to_uppercase().as_str()
+                        {
+                            "DATA" => {
+                                data = Some(vec![]);
+                                b"354 go ahead\r\n"
+                            }
+                            "QUIT" => b"221 bye\r\n",
+                            _ => b"250 ok\r\n",
+                        };
+                        writer.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        SmtpSink { port, messages }
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+// A message the MQTT broker was sent
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+// Just enough of an MQTT 3.1.1 broker on a free local port to accept clients and keep what they publish
+pub struct MqttBroker {
+    pub port: u16,
+    published: Arc<Mutex<Vec<Published>>>,
+}
+
+impl MqttBroker {
+    pub async fn start() -> MqttBroker {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let published = Arc::new(Mutex::new(vec![]));
+        let received = published.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let received = received.clone();
+                tokio::spawn(async move {
+                    while let Ok((header, body)) = read_mqtt_packet(&mut stream).await {
+                        let reply = match header >> 4 {
+                            // CONNECT, accepted
+                            1 => vec![0x20, 0x02, 0x00, 0x00],
+                            // PUBLISH, acknowledged when it's QoS 1
+                            3 => {
+                                let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                                let topic = &body[2..2 + topic_length];
+                                let mut payload = &body[2 + topic_length..];
+                                let mut reply = vec![];
+                                if (header >> 1) & 0x03 > 0 {
+                                    reply = vec![0x40, 0x02, payload[0], payload[1]];
+                                    payload = &payload[2..];
+                                }
+                                received.lock().unwrap().push(Published {
+                                    topic: String::from_utf8_lossy(topic).into_owned(),
+                                    payload: String::from_utf8_lossy(payload).into_owned(),
+                                    retain: header & 0x01 == 1,
+                                });
+                                reply
+                            }
+                            // PINGREQ
+                            12 => vec![0xd0, 0x00],
+                            _ => vec![],
+                        };
+                        if stream.write_all(&reply).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        MqttBroker { port, published }
+    }
+
+    // Everything published so far, once there's at least `count` of it or a few seconds have passed
+    pub async fn published(&self, count: usize) -> Vec<Published> {
+        for _ in 0..50 {
+            if self.published.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+        }
+        self.published.lock().unwrap().clone()
+    }
+}
+
+// A packet's fixed header byte and everything after its length
+async fn read_mqtt_packet(stream: &mut TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8];
+    stream.read_exact(&mut byte).await?;
+    let header = byte[0];
+
+    // The remaining length is sent 7 bits at a time, lowest first
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        stream.read_exact(&mut byte).await?;
+        length |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+    Ok((header, body))
+}
+
+pub fn product(name: &str) -> Product {
+    Product::BestBuy(ProductDetails {
+        product: name.to_string(),
+        page: format!("https://www.bestbuy.com/site/{}", name.replace(' ', "-")),
+        ..ProductDetails::default()
+    })
+    .with_offer(Offer {
+        price_cents: Some(69_999),
+        image_url: Some("https://example.com/card.jpg".to_string()),
+        ..Offer::default()
+    })
+}
+
+pub fn alert(product: Product) -> Alert {
+    Alert {
+        message: product.new_stock_message(),
+        summary: product.new_stock_message(),
+        detected_at: Local::now(),
+        snapshot_path: None,
+        product,
+    }
+}
+
+// A notifier with nothing connected, from the application config and subscribers given
+pub fn notifier(application_config: serde_json::Value, subscribers: serde_json::Value) -> Notifier {
+    let mut config = serde_json::json!({
+        "last_seen_evga": "2020-01-01T00:00:00-00:00",
+        "last_seen_newegg": "2020-01-01T00:00:00-00:00",
+        "last_seen_asus": "2020-01-01T00:00:00-00:00",
+        "last_notification_sent": "2020-01-01T00:00:00-00:00",
+        "should_open_browser": false,
+        "daemon_mode": false,
+    });
+    for (key, value) in application_config.as_object().unwrap() {
+        config[key] = value.clone();
+    }
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "application_config": config,
+        "subscribers": subscribers,
+        "products": [],
+    }))
+    .unwrap();
+
+    Notifier {
+        twilio: None,
+        imap: None,
+        smtp: None,
+        mqtt: None,
+        mail_rules: vec![],
+        delivery_failures: vec![],
+        spend_ledger: Default::default(),
+        config,
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{Local, NaiveTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::error::NotifyError;
+use crate::notifier::twilio::TextChannel;
+use crate::product::{tag_expression_matches, Product, ShipsFrom, TagFilter};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Subscriber {
+    pub service: Vec<String>,
+    pub to_phone_number: Option<String>,
+    // Whether texts go out as sms, mms or whatsapp. Defaults to sms
+    pub text_channel: Option<TextChannel>,
+    // Why texts to this number were turned off after Twilio said it can't be reached. Remove it to text them again
+    pub sms_disabled: Option<String>,
+    // Telegram chat to message, instead of or as well as texting
+    pub telegram_chat_id: Option<i64>,
+    // Email address to send alerts to
+    pub email: Option<String>,
+    // Name of the push target in `push_targets` to send alerts to
+    pub push_target: Option<String>,
+    pub active: bool,
+    // Catalog ids the subscriber wants at any retailer, regardless of `service`
+    pub catalog: Option<Vec<String>>,
+    // Tag filters keyed by provider key. The "*" key applies to any provider without its own filter
+    pub tags: Option<HashMap<String, TagFilter>>,
+    // Highest price the subscriber will pay, in dollars
+    pub max_price: Option<f64>,
+    // Highest price per product tag, in dollars. The lowest matching ceiling wins
+    pub tag_max_price: Option<HashMap<String, f64>>,
+    // Sellers the subscriber never wants to buy from, matched case insensitively
+    pub excluded_sellers: Option<Vec<String>>,
+    // The least preferred shipping arrangement the subscriber will accept
+    pub min_ships_from: Option<ShipsFrom>,
+    // IANA time zone name, like "America/Los_Angeles". Defaults to the machine's local time
+    pub time_zone: Option<String>,
+    pub quiet_hours: Option<QuietHours>,
+    // Tag expressions that are always sent, even during quiet hours
+    pub always_notify_tags: Option<Vec<String>>,
+    // Locale for message templates, like "en" or "de-AT". Defaults to the application's locale
+    pub locale: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuietHours {
+    // Times in the subscriber's time zone, like "22:00:00". The range may wrap past midnight
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub mode: QuietMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuietMode {
+    // Drop anything found during quiet hours
+    Suppress,
+    // Hold anything found during quiet hours and send it all once they end
+    Digest,
+}
+
+// Every subscriber needs a way to be reached, and no two can share the id their state is kept under
+pub fn validate(subscribers: &[Subscriber]) -> Result<(), NotifyError> {
+    let mut ids = HashSet::new();
+    for (i, subscriber) in subscribers.iter().enumerate() {
+        let id = subscriber.id();
+        if id.is_empty() {
+            return Err(NotifyError::SubscriberConfig(format!(
+                "subscriber {} has no phone number, telegram chat, email or push target",
+                i + 1
+            )));
+        }
+        if !ids.insert(id.clone()) {
+            return Err(NotifyError::SubscriberConfig(format!(
+                "more than one subscriber is {}, merge them into one",
+                id
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl Subscriber {
+    // A stable name for the subscriber, used to key their state and in logs
+    // Taken from how they're reached, `validate` makes sure there's something and that it's unique
+    pub fn id(&self) -> String {
+        match (
+            &self.to_phone_number,
+            self.telegram_chat_id,
+            &self.email,
+            &self.push_target,
+        ) {
+            (Some(phone), _, _, _) => phone.clone(),
+            (None, Some(chat_id), _, _) => format!("telegram:{}", chat_id),
+            (None, None, Some(email), _) => email.clone(),
+            (None, None, None, Some(push_target)) => format!("push:{}", push_target),
+            (None, None, None, None) => String::new(),
+        }
+    }
+
+    // Whether texts to the subscriber go out at all
+    pub fn can_be_texted(&self) -> bool {
+        self.to_phone_number.is_some() && self.sms_disabled.is_none()
+    }
+
+    // Filter to only active subscribers that want this product, by provider and tags or by card,
+    // and whose filters accept the offer that was found
+    pub fn should_notify(&self, product: &Product) -> bool {
+        self.active && self.wants(product) && self.accepts_offer(product)
+    }
+
+    // Whether this subscriber wants to hear about this product
+    pub fn wants(&self, product: &Product) -> bool {
+        let key = product.to_key();
+
+        let wants_card = match (&self.catalog, product.get_catalog_id()) {
+            (Some(catalog), Some(id)) => catalog.iter().any(|c| c == id),
+            _ => false,
+        };
+
+        let passes_tags = match self.tag_filter(key) {
+            Some(filter) => filter.matches(product.get_tags()),
+            None => true,
+        };
+        let wants_provider = self.service.iter().any(|service| service == key) && passes_tags;
+
+        wants_provider || wants_card
+    }
+
+    // Whether the offer a scraper reported passes this subscriber's price, seller and shipping filters
+    // Anything the scraper couldn't tell us about is let through
+    pub fn accepts_offer(&self, product: &Product) -> bool {
+        let offer = match product.get_offer() {
+            Some(offer) => offer,
+            None => return true,
+        };
+
+        if let (Some(price), Some(ceiling)) = (offer.price(), self.price_ceiling(product)) {
+            if price > ceiling {
+                return false;
+            }
+        }
+
+        if let (Some(seller), Some(excluded)) = (&offer.seller, &self.excluded_sellers) {
+            let seller = seller.to_ascii_lowercase();
+            if excluded
+                .iter()
+                .any(|excluded| seller.contains(&excluded.to_ascii_lowercase()))
+            {
+                return false;
+            }
+        }
+
+        match (offer.ships_from, self.min_ships_from) {
+            (Some(ships_from), Some(min)) => ships_from >= min,
+            _ => true,
+        }
+    }
+
+    fn price_ceiling(&self, product: &Product) -> Option<f64> {
+        // Tags are matched case insensitively everywhere else, so ceilings are too
+        let tags = product.get_tags();
+        let tag_ceilings = self.tag_max_price.iter().flat_map(|ceilings| {
+            ceilings
+                .iter()
+                .filter(move |(tag, _)| tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+                .map(|(_, ceiling)| *ceiling)
+        });
+
+        self.max_price
+            .into_iter()
+            .chain(tag_ceilings)
+            .fold(None, |lowest: Option<f64>, ceiling| {
+                Some(lowest.map_or(ceiling, |lowest| lowest.min(ceiling)))
+            })
+    }
+
+    // The current wall clock time where the subscriber lives
+    fn local_time(&self) -> NaiveTime {
+        match self.time_zone.as_ref().map(|tz| tz.parse::<Tz>()) {
+            Some(Ok(tz)) => Utc::now().with_timezone(&tz).time(),
+            Some(Err(e)) => {
+                eprintln!("Invalid time zone for {}: {}", self.id(), e);
+                Local::now().time()
+            }
+            None => Local::now().time(),
+        }
+    }
+
+    // The quiet hours mode if the subscriber is currently in quiet hours
+    pub fn quiet_mode(&self) -> Option<QuietMode> {
+        let quiet_hours = self.quiet_hours.as_ref()?;
+        let now = self.local_time();
+        let is_quiet = if quiet_hours.start <= quiet_hours.end {
+            now >= quiet_hours.start && now < quiet_hours.end
+        } else {
+            // The range wraps past midnight
+            now >= quiet_hours.start || now < quiet_hours.end
+        };
+
+        if is_quiet {
+            Some(quiet_hours.mode)
+        } else {
+            None
+        }
+    }
+
+    // Whether this product is important enough to wake the subscriber up
+    pub fn overrides_quiet_hours(&self, product: &Product) -> bool {
+        match &self.always_notify_tags {
+            Some(exprs) => exprs
+                .iter()
+                .any(|expr| tag_expression_matches(expr, product.get_tags())),
+            None => false,
+        }
+    }
+
+    fn tag_filter(&self, key: &str) -> Option<&TagFilter> {
+        let tags = self.tags.as_ref()?;
+        tags.get(key).or_else(|| tags.get("*"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::product::{Offer, ProductDetails};
+
+    fn subscriber(json: serde_json::Value) -> Subscriber {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn product(tags: &[&str], price_cents: u64) -> Product {
+        Product::BestBuy(ProductDetails {
+            product: "RTX 3080".to_string(),
+            page: "https://example.com/3080".to_string(),
+            tags: Some(tags.iter().map(|tag| tag.to_string()).collect()),
+            ..ProductDetails::default()
+        })
+        .with_offer(Offer {
+            price_cents: Some(price_cents),
+            ..Offer::default()
+        })
+    }
+
+    #[test]
+    fn subscribers_need_unique_contact_details() {
+        let phone = subscriber(serde_json::json!({
+            "service": [], "active": true, "to_phone_number": "+15551112222",
+        }));
+        let push = subscriber(serde_json::json!({
+            "service": [], "active": true, "push_target": "phone",
+        }));
+        let nobody = subscriber(serde_json::json!({ "service": [], "active": true }));
+
+        assert_eq!(push.id(), "push:phone");
+        assert!(validate(&[phone.clone(), push.clone()]).is_ok());
+        assert!(validate(&[phone.clone(), nobody]).is_err());
+        assert!(validate(&[phone.clone(), push, phone]).is_err());
+    }
+
+    #[test]
+    fn tag_max_price_ignores_case() {
+        let subscriber = subscriber(serde_json::json!({
+            "service": ["bestbuy"],
+            "active": true,
+            "tag_max_price": { "FE": 750.0 },
+        }));
+
+        assert!(subscriber.should_notify(&product(&["fe"], 69_999)));
+        assert!(!subscriber.should_notify(&product(&["fe"], 79_999)));
+        assert!(subscriber.should_notify(&product(&["3080"], 79_999)));
+    }
+
+    #[test]
+    fn lowest_ceiling_wins() {
+        let subscriber = subscriber(serde_json::json!({
+            "service": ["bestbuy"],
+            "active": true,
+            "max_price": 900.0,
+            "tag_max_price": { "3080": 800.0, "fe": 750.0 },
+        }));
+
+        assert!(!subscriber.should_notify(&product(&["3080", "FE"], 79_999)));
+        assert!(subscriber.should_notify(&product(&["3080"], 79_999)));
+    }
+}